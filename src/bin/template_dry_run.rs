@@ -0,0 +1,43 @@
+//! Checks templates against message samples before they are deployed.
+//!
+//! Usage: `template_dry_run <templates.json> <samples.json>`
+//!
+//! `templates.json` is an array of `NotificationTemplate`, `samples.json` an array of
+//! `{"message": TextMessage, "send_mode": SendModeEnum | null, "expected": Event | null}`.
+//! Exits with 1 if any template does not compile, or any sample fails or is matched
+//! by several templates of the same priority.
+
+use std::process::ExitCode;
+use serde::de::DeserializeOwned;
+use send_mode_lib::send_modes::dry_run::{DryRun, Sample};
+use send_mode_lib::send_modes::notification_types::NotificationTemplate;
+
+fn read<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    serde_json::from_slice(&data).map_err(|e| format!("{path}: {e}"))
+}
+
+fn run(templates_path: &str, samples_path: &str) -> Result<bool, String> {
+    let templates: Vec<NotificationTemplate> = read(templates_path)?;
+    let samples: Vec<Sample> = read(samples_path)?;
+    let dry_run = DryRun::new(templates);
+    let report = dry_run.run(&samples);
+    println!("{report}");
+    Ok(report.is_ok())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, templates, samples] = args.as_slice() else {
+        eprintln!("usage: template_dry_run <templates.json> <samples.json>");
+        return ExitCode::from(2)
+    };
+    match run(templates, samples) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(2)
+        }
+    }
+}
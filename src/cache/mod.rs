@@ -0,0 +1,91 @@
+pub mod read_through;
+
+use std::future::Future;
+use std::time::Duration;
+use crate::repository::send_mode_repository::SendModeRepository;
+use crate::repository::template_repository::{TemplateFilter, TemplateRepository};
+use crate::send_modes::error::LibError;
+use crate::send_modes::notification_types::NotificationTemplate;
+use crate::send_modes::send_mode::{RenameSendModeRequest, SendMode, SendModeEnum};
+use crate::tools::send_mode_client::SendModeClient;
+
+pub trait SendModeSource {
+    fn get_send_mode(&self, id: &str) -> impl Future<Output = Result<SendMode, LibError>> + Send;
+    fn delete_send_mode(&self, id: &str) -> impl Future<Output = Result<(), LibError>> + Send;
+}
+
+pub trait RenameSendMode {
+    fn rename_send_mode(&self, request: &RenameSendModeRequest)
+        -> impl Future<Output = Result<SendMode, LibError>> + Send;
+}
+
+pub trait TemplateSource {
+    fn templates_by_send_mode(&self, send_mode: &SendModeEnum)
+        -> impl Future<Output = Result<Vec<NotificationTemplate>, LibError>> + Send;
+}
+
+impl<S: SendModeSource + Sync> SendModeSource for &S {
+    async fn get_send_mode(&self, id: &str) -> Result<SendMode, LibError> {
+        S::get_send_mode(self, id).await
+    }
+
+    async fn delete_send_mode(&self, id: &str) -> Result<(), LibError> {
+        S::delete_send_mode(self, id).await
+    }
+}
+
+impl SendModeSource for SendModeClient {
+    async fn get_send_mode(&self, id: &str) -> Result<SendMode, LibError> {
+        self.get_send_mode_by_id(id).await
+    }
+
+    async fn delete_send_mode(&self, id: &str) -> Result<(), LibError> {
+        SendModeClient::delete_send_mode(self, id).await
+    }
+}
+
+impl RenameSendMode for SendModeClient {
+    async fn rename_send_mode(&self, request: &RenameSendModeRequest) -> Result<SendMode, LibError> {
+        SendModeClient::rename_send_mode(self, request).await
+    }
+}
+
+impl SendModeSource for SendModeRepository {
+    async fn get_send_mode(&self, id: &str) -> Result<SendMode, LibError> {
+        self.get_by_id(id).await
+    }
+
+    async fn delete_send_mode(&self, id: &str) -> Result<(), LibError> {
+        self.delete(id).await
+    }
+}
+
+impl TemplateSource for TemplateRepository {
+    async fn templates_by_send_mode(&self, send_mode: &SendModeEnum) -> Result<Vec<NotificationTemplate>, LibError> {
+        let filter = TemplateFilter { send_mode: Some(send_mode.clone()), ..TemplateFilter::default() };
+        Ok(self.list(&filter).await?.into_iter().map(|stored| stored.template).collect())
+    }
+}
+
+impl RenameSendMode for SendModeRepository {
+    async fn rename_send_mode(&self, request: &RenameSendModeRequest) -> Result<SendMode, LibError> {
+        self.rename(request).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub namespace: String,
+    pub ttl: Duration,
+    pub negative_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            namespace: "send_mode_lib".to_owned(),
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(10),
+        }
+    }
+}
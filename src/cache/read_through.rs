@@ -0,0 +1,363 @@
+use std::time::Duration;
+use deadpool_redis::redis::{self, AsyncCommands};
+use deadpool_redis::{Connection, Pool};
+use tracing::{debug, warn};
+use crate::cache::{CacheConfig, RenameSendMode, SendModeSource, TemplateSource};
+use crate::send_modes::error::LibError;
+use crate::send_modes::notification_types::NotificationTemplate;
+use crate::send_modes::send_mode::{RenameSendModeRequest, SendMode, SendModeEnum};
+
+/// How long an invalidation is remembered, it must outlive any load in flight.
+const GENERATION_TTL: Duration = Duration::from_secs(3600);
+
+/// Redis rejects a zero expiry, so a TTL under a millisecond is rounded up.
+fn millis(ttl: Duration) -> u64 {
+    (ttl.as_millis() as u64).max(1)
+}
+
+/// Redis errors never fail a read, they are logged and the source is asked directly.
+pub struct ReadThroughCache<S> {
+    source: S,
+    pool: Pool,
+    config: CacheConfig,
+}
+
+impl<S> ReadThroughCache<S> {
+    pub fn new(source: S, pool: Pool) -> Self {
+        Self::with_config(source, pool, CacheConfig::default())
+    }
+
+    pub fn with_config(source: S, pool: Pool, config: CacheConfig) -> Self {
+        Self { source, pool, config }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    fn send_mode_key(&self, id: &str) -> String {
+        format!("{}:send_mode:{}", self.config.namespace, id)
+    }
+
+    fn missing_send_mode_key(&self, id: &str) -> String {
+        format!("{}:send_mode_missing:{}", self.config.namespace, id)
+    }
+
+    fn send_mode_generation_key(&self, id: &str) -> String {
+        format!("{}:send_mode_generation:{}", self.config.namespace, id)
+    }
+
+    fn templates_key(&self, send_mode: &SendModeEnum) -> String {
+        format!("{}:templates:{}", self.config.namespace, send_mode)
+    }
+
+    fn templates_generation_key(&self, send_mode: &SendModeEnum) -> String {
+        format!("{}:templates_generation:{}", self.config.namespace, send_mode)
+    }
+
+    async fn connection(&self) -> Option<Connection> {
+        self.pool.get().await
+            .inspect_err(|e| warn!(err=e.to_string(), "Get redis connection error"))
+            .ok()
+    }
+
+    pub async fn invalidate_send_mode(&self, id: &str) {
+        let keys = [self.send_mode_key(id), self.missing_send_mode_key(id)];
+        if let Err(e) = self.invalidate(&keys, &self.send_mode_generation_key(id)).await {
+            warn!(err=e.to_string(), id=id, "Send mode cache invalidation error");
+        }
+    }
+
+    pub async fn invalidate_templates(&self, send_mode: &SendModeEnum) {
+        let keys = [self.templates_key(send_mode)];
+        if let Err(e) = self.invalidate(&keys, &self.templates_generation_key(send_mode)).await {
+            warn!(err=e.to_string(), send_mode=send_mode.to_string(), "Template cache invalidation error");
+        }
+    }
+
+    async fn invalidate(&self, keys: &[String], generation_key: &str) -> redis::RedisResult<()> {
+        let Some(mut conn) = self.connection().await else {
+            return Ok(())
+        };
+        redis::pipe()
+            .atomic()
+            .del(keys).ignore()
+            .incr(generation_key, 1).ignore()
+            .pexpire(generation_key, GENERATION_TTL.as_millis() as i64).ignore()
+            .query_async(&mut conn)
+            .await
+    }
+
+    async fn write_back(&self, generation_key: &str, seen: Option<u64>, write: &mut redis::Pipeline) -> redis::RedisResult<bool> {
+        let Some(mut conn) = self.connection().await else {
+            return Ok(false)
+        };
+        redis::cmd("WATCH").arg(generation_key).query_async::<()>(&mut conn).await?;
+        let current: Option<u64> = conn.get(generation_key).await?;
+        if current != seen {
+            redis::cmd("UNWATCH").query_async::<()>(&mut conn).await?;
+            debug!(key=generation_key, "Cache entry invalidated while loading, not written back");
+            return Ok(false)
+        }
+        // EXEC returns nil if the generation changed after WATCH
+        let written: Option<()> = write.atomic().query_async(&mut conn).await?;
+        Ok(written.is_some())
+    }
+}
+
+impl<S: SendModeSource> ReadThroughCache<S> {
+    pub async fn get_send_mode(&self, id: &str) -> Result<SendMode, LibError> {
+        let generation_key = self.send_mode_generation_key(id);
+        // Generation seen on a miss, `None` if the cache could not be read
+        let mut generation = None;
+        if let Some(mut conn) = self.connection().await {
+            let cached: redis::RedisResult<(Option<SendMode>, bool, Option<u64>)> = redis::pipe()
+                .get(self.send_mode_key(id))
+                .exists(self.missing_send_mode_key(id))
+                .get(&generation_key)
+                .query_async(&mut conn)
+                .await;
+            match cached {
+                Ok((Some(send_mode), _, _)) => return Ok(send_mode),
+                Ok((None, true, _)) => {
+                    debug!(id=id, "Send mode is cached as missing");
+                    return Err(LibError::not_found(id))
+                }
+                Ok((None, false, seen)) => generation = Some(seen),
+                Err(e) => warn!(err=e.to_string(), id=id, "Send mode cache read error"),
+            }
+        }
+
+        let result = self.source.get_send_mode(id).await;
+        let Some(seen) = generation else {
+            return result
+        };
+        let mut write = redis::pipe();
+        match &result {
+            Ok(send_mode) => write.pset_ex(self.send_mode_key(id), send_mode, millis(self.config.ttl)).ignore(),
+            Err(LibError::NotFound(_)) => write.pset_ex(self.missing_send_mode_key(id), 1, millis(self.config.negative_ttl)).ignore(),
+            Err(_) => return result,
+        };
+        if let Err(e) = self.write_back(&generation_key, seen, &mut write).await {
+            warn!(err=e.to_string(), id=id, "Send mode cache write error");
+        }
+        result
+    }
+
+    pub async fn delete_send_mode(&self, id: &str) -> Result<(), LibError> {
+        let result = self.source.delete_send_mode(id).await;
+        self.invalidate_send_mode(id).await;
+        result
+    }
+}
+
+impl<S: SendModeSource + RenameSendMode> ReadThroughCache<S> {
+    pub async fn rename_send_mode(&self, request: &RenameSendModeRequest) -> Result<SendMode, LibError> {
+        let result = self.source.rename_send_mode(request).await;
+        self.invalidate_send_mode(&request.id).await;
+        result
+    }
+}
+
+impl<S: TemplateSource> ReadThroughCache<S> {
+    pub async fn templates_by_send_mode(&self, send_mode: &SendModeEnum) -> Result<Vec<NotificationTemplate>, LibError> {
+        let key = self.templates_key(send_mode);
+        let generation_key = self.templates_generation_key(send_mode);
+        // Generation seen on a miss, `None` if the cache could not be read
+        let mut generation = None;
+        if let Some(mut conn) = self.connection().await {
+            let cached: redis::RedisResult<(Vec<NotificationTemplate>, Option<u64>)> = redis::pipe()
+                .lrange(&key, 0, -1)
+                .get(&generation_key)
+                .query_async(&mut conn)
+                .await;
+            match cached {
+                Ok((templates, _)) if !templates.is_empty() => return Ok(templates),
+                Ok((_, seen)) => generation = Some(seen),
+                Err(e) => warn!(err=e.to_string(), "Template cache read error"),
+            }
+        }
+
+        let templates = self.source.templates_by_send_mode(send_mode).await?;
+        if let Some(seen) = generation && !templates.is_empty() {
+            let mut write = redis::pipe();
+            write.del(&key).ignore()
+                .rpush(&key, templates.as_slice()).ignore()
+                .pexpire(&key, millis(self.config.ttl) as i64).ignore();
+            if let Err(e) = self.write_back(&generation_key, seen, &mut write).await {
+                warn!(err=e.to_string(), "Template cache write error");
+            }
+        }
+        Ok(templates)
+    }
+}
+
+impl<S: SendModeSource + Sync> SendModeSource for ReadThroughCache<S> {
+    async fn get_send_mode(&self, id: &str) -> Result<SendMode, LibError> {
+        ReadThroughCache::get_send_mode(self, id).await
+    }
+
+    async fn delete_send_mode(&self, id: &str) -> Result<(), LibError> {
+        ReadThroughCache::delete_send_mode(self, id).await
+    }
+}
+
+impl<S: TemplateSource + Sync> TemplateSource for ReadThroughCache<S> {
+    async fn templates_by_send_mode(&self, send_mode: &SendModeEnum) -> Result<Vec<NotificationTemplate>, LibError> {
+        ReadThroughCache::templates_by_send_mode(self, send_mode).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+    use super::*;
+    use crate::ingest::{Authenticate, SourceAuthenticator};
+    use crate::send_modes::event::{EventType, SendEvent};
+    use crate::send_modes::fixtures;
+    use crate::send_modes::provider::ProviderRegistry;
+    use crate::tools::fake_redis::FakeRedis;
+    use crate::tools::signing::Credential;
+
+    #[derive(Default)]
+    struct Gate {
+        started: Notify,
+        release: Notify,
+    }
+
+    #[derive(Default)]
+    struct Source {
+        loads: AtomicUsize,
+        gate: Option<Gate>,
+    }
+
+    impl Source {
+        async fn load(&self) {
+            self.loads.fetch_add(1, Ordering::SeqCst);
+            if let Some(gate) = &self.gate {
+                gate.started.notify_one();
+                gate.release.notified().await;
+            }
+        }
+
+        fn loads(&self) -> usize {
+            self.loads.load(Ordering::SeqCst)
+        }
+    }
+
+    impl SendModeSource for Source {
+        async fn get_send_mode(&self, id: &str) -> Result<SendMode, LibError> {
+            self.load().await;
+            if id == "missing" {
+                return Err(LibError::not_found(id))
+            }
+            Ok(fixtures::send_mode(id))
+        }
+
+        async fn delete_send_mode(&self, _id: &str) -> Result<(), LibError> {
+            Ok(())
+        }
+    }
+
+    impl TemplateSource for Source {
+        async fn templates_by_send_mode(&self, send_mode: &SendModeEnum) -> Result<Vec<NotificationTemplate>, LibError> {
+            self.load().await;
+            Ok(vec![NotificationTemplate { send_mode: send_mode.clone(), ..fixtures::template("Зачисление {amount}р") }])
+        }
+    }
+
+    fn gated() -> Source {
+        Source { gate: Some(Gate::default()), ..Source::default() }
+    }
+
+    #[tokio::test]
+    async fn caches_found_and_missing_send_modes() {
+        let redis = FakeRedis::start().await;
+        let cache = ReadThroughCache::new(Source::default(), redis.pool());
+        assert_eq!(cache.get_send_mode("mode").await.unwrap().id, "mode");
+        assert_eq!(cache.get_send_mode("mode").await.unwrap().id, "mode");
+        assert!(matches!(cache.get_send_mode("missing").await, Err(LibError::NotFound(_))));
+        assert!(matches!(cache.get_send_mode("missing").await, Err(LibError::NotFound(_))));
+        assert_eq!(cache.source().loads(), 2);
+
+        cache.invalidate_send_mode("mode").await;
+        cache.get_send_mode("mode").await.unwrap();
+        assert_eq!(cache.source().loads(), 3);
+    }
+
+    #[tokio::test]
+    async fn authenticates_through_cache() {
+        let redis = FakeRedis::start().await;
+        let cache = ReadThroughCache::new(Source::default(), redis.pool());
+        let authenticator = SourceAuthenticator::new(&cache, ProviderRegistry::default());
+        let event = SendEvent {
+            source: "900".to_owned(),
+            text: "Зачисление 10р".to_owned(),
+            event_type: EventType::SMS,
+            subject: None,
+            sender: None,
+        };
+        for _ in 0..2 {
+            authenticator.authenticate("mode", Credential::AccessToken("token"), &event).await.unwrap();
+        }
+        assert_eq!(cache.source().loads(), 1);
+    }
+
+    #[tokio::test]
+    async fn subsecond_ttl_is_still_written() {
+        let redis = FakeRedis::start().await;
+        let config = CacheConfig {
+            ttl: Duration::from_millis(500),
+            negative_ttl: Duration::ZERO,
+            ..CacheConfig::default()
+        };
+        let cache = ReadThroughCache::with_config(Source::default(), redis.pool(), config);
+        cache.get_send_mode("mode").await.unwrap();
+        cache.get_send_mode("missing").await.unwrap_err();
+        cache.templates_by_send_mode(&SendModeEnum::KRAFT).await.unwrap();
+        assert!(redis.get(&cache.send_mode_key("mode")).is_some());
+        assert!(redis.get(&cache.missing_send_mode_key("missing")).is_some());
+        assert_eq!(redis.list(&cache.templates_key(&SendModeEnum::KRAFT)).len(), 1);
+    }
+
+    /// A single connection pool also checks that none is held while the source loads,
+    /// otherwise the invalidation could not get one and the test would time out.
+    #[tokio::test]
+    async fn invalidation_during_load_wins() {
+        let redis = FakeRedis::start().await;
+        let cache = ReadThroughCache::new(gated(), redis.single_connection_pool());
+        let gate = cache.source().gate.as_ref().unwrap();
+        let (loaded, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(cache.get_send_mode("mode"), async {
+                gate.started.notified().await;
+                cache.invalidate_send_mode("mode").await;
+                gate.release.notify_one();
+            })
+        }).await.unwrap();
+        assert_eq!(loaded.unwrap().id, "mode");
+        assert!(redis.get(&cache.send_mode_key("mode")).is_none());
+    }
+
+    #[tokio::test]
+    async fn template_invalidation_during_load_wins() {
+        let redis = FakeRedis::start().await;
+        let cache = ReadThroughCache::new(gated(), redis.single_connection_pool());
+        let gate = cache.source().gate.as_ref().unwrap();
+        let send_mode = SendModeEnum::KRAFT;
+        let (loaded, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(cache.templates_by_send_mode(&send_mode), async {
+                gate.started.notified().await;
+                cache.invalidate_templates(&send_mode).await;
+                gate.release.notify_one();
+            })
+        }).await.unwrap();
+        assert_eq!(loaded.unwrap().len(), 1);
+        assert!(redis.list(&cache.templates_key(&send_mode)).is_empty());
+
+        // Without a concurrent invalidation the next load is written back
+        gate.release.notify_one();
+        cache.templates_by_send_mode(&send_mode).await.unwrap();
+        assert_eq!(redis.list(&cache.templates_key(&send_mode)).len(), 1);
+    }
+}
@@ -0,0 +1,196 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use deadpool_redis::redis::{self, AsyncCommands};
+use deadpool_redis::Pool;
+use rsa::sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use crate::send_modes::error::LibError;
+use crate::send_modes::event::{Event, EventType};
+
+#[derive(Debug, Clone)]
+pub struct DedupConfig {
+    pub namespace: String,
+    pub window: Duration,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            namespace: "send_mode_lib".to_owned(),
+            window: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirstSeen {
+    pub event_type: EventType,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DedupOutcome {
+    Unique,
+    Duplicate { fingerprint: String, first_seen: Option<FirstSeen> },
+}
+
+impl DedupOutcome {
+    pub fn is_duplicate(&self) -> bool {
+        matches!(self, DedupOutcome::Duplicate { .. })
+    }
+}
+
+/// Only fields every channel extracts take part, an SMS often carries the balance while the push does not.
+pub fn fingerprint(event: &Event) -> String {
+    let identity = [
+        event.mode_id.as_str(),
+        event.bank.as_str(),
+        &event.kind.to_string(),
+        &format!("{} {}", event.amount.amount.normalize(), event.amount.currency),
+    ].join("\n");
+    Sha256::digest(identity.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Needs Redis 7.0 or newer for `SET NX GET`.
+pub struct Deduplicator {
+    pool: Pool,
+    config: DedupConfig,
+}
+
+impl Deduplicator {
+    pub fn new(pool: Pool) -> Self {
+        Self::with_config(pool, DedupConfig::default())
+    }
+
+    pub fn with_config(pool: Pool, config: DedupConfig) -> Self {
+        Self { pool, config }
+    }
+
+    fn key(&self, fingerprint: &str) -> String {
+        format!("{}:event:{}", self.config.namespace, fingerprint)
+    }
+
+    /// Redis errors are returned, not treated as unique, so an outage cannot confirm a payment twice.
+    pub async fn check(&self, event: &Event, event_type: &EventType) -> Result<DedupOutcome, LibError> {
+        let fingerprint = fingerprint(event);
+        let key = self.key(&fingerprint);
+        let first_seen = FirstSeen { event_type: event_type.clone(), at: Utc::now() };
+        let value = serde_json::to_string(&first_seen).map_err(|e| {
+            error!(err=e.to_string(), "First seen serialize error");
+            LibError::InternalServerError
+        })?;
+        let mut conn = self.pool.get().await.map_err(|e| Self::redis_error(e.to_string()))?;
+        // Claims the key and returns the previous value in one call, nil means it was claimed
+        let stored: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(value)
+            .arg("NX")
+            .arg("GET")
+            .arg("EX")
+            .arg(self.config.window.as_secs().max(1))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Self::redis_error(e.to_string()))?;
+        let Some(stored) = stored else {
+            return Ok(DedupOutcome::Unique)
+        };
+        let first_seen = serde_json::from_str::<FirstSeen>(&stored).ok();
+        warn!(
+            mode_id=event.mode_id,
+            fingerprint=fingerprint,
+            event_type=event_type.to_string(),
+            first_event_type=first_seen.as_ref().map(|f| f.event_type.to_string()),
+            "Duplicate event suppressed"
+        );
+        Ok(DedupOutcome::Duplicate { fingerprint, first_seen })
+    }
+
+    pub async fn forget(&self, event: &Event) -> Result<(), LibError> {
+        let mut conn = self.pool.get().await.map_err(|e| Self::redis_error(e.to_string()))?;
+        conn.del::<_, ()>(self.key(&fingerprint(event))).await.map_err(|e| Self::redis_error(e.to_string()))
+    }
+
+    fn redis_error(e: String) -> LibError {
+        error!(err=e, "Deduplication redis error");
+        LibError::RedisError(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use chrono::NaiveDateTime;
+    use rust_decimal::Decimal;
+    use super::*;
+    use crate::send_modes::event::TransactionKind;
+    use crate::tools::fake_redis::FakeRedis;
+    use crate::send_modes::money::{Currency, Money};
+
+    fn event(amount: &str, balance: Option<&str>) -> Event {
+        let money = |value: &str| Money::new(Decimal::from_str(value).unwrap(), Currency::RUB);
+        Event {
+            mode_id: "mode".to_owned(),
+            bank: "sber".to_owned(),
+            amount: money(amount),
+            requisite: Some("*1234".to_owned()),
+            balance: balance.map(money),
+            search_by: "amount".to_owned(),
+            kind: TransactionKind::Credit,
+            counterparty: None,
+            card: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn fingerprint_ignores_scale_and_channel_fields() {
+        assert_eq!(fingerprint(&event("10.0", Some("100"))), fingerprint(&event("10.00", None)));
+        let mut detailed = event("10", None);
+        detailed.requisite = None;
+        detailed.card = Some("*1234".to_owned());
+        detailed.counterparty = Some("Иван И.".to_owned());
+        detailed.timestamp = NaiveDateTime::parse_from_str("2025-06-01 10:15:00", "%Y-%m-%d %H:%M:%S").ok();
+        assert_eq!(fingerprint(&event("10", None)), fingerprint(&detailed));
+        assert_ne!(fingerprint(&event("10", None)), fingerprint(&event("11", None)));
+        let mut debit = event("10", None);
+        debit.kind = TransactionKind::Debit;
+        assert_ne!(fingerprint(&event("10", None)), fingerprint(&debit));
+    }
+
+    #[tokio::test]
+    async fn push_without_balance_duplicates_sms() {
+        let redis = FakeRedis::start().await;
+        let dedup = Deduplicator::new(redis.pool());
+        let sms = event("10", Some("100"));
+        let push = event("10", None);
+        assert_eq!(dedup.check(&sms, &EventType::SMS).await.unwrap(), DedupOutcome::Unique);
+        assert!(dedup.check(&push, &EventType::PUSH).await.unwrap().is_duplicate());
+    }
+
+    #[tokio::test]
+    async fn check_claims_once_until_forgotten() {
+        let redis = FakeRedis::start().await;
+        let dedup = Deduplicator::new(redis.pool());
+        let event = event("10", None);
+        assert_eq!(dedup.check(&event, &EventType::SMS).await.unwrap(), DedupOutcome::Unique);
+        let DedupOutcome::Duplicate { fingerprint: seen, first_seen } = dedup.check(&event, &EventType::PUSH).await.unwrap() else {
+            panic!("second delivery is not a duplicate")
+        };
+        assert_eq!(seen, fingerprint(&event));
+        assert_eq!(first_seen.unwrap().event_type, EventType::SMS);
+        dedup.forget(&event).await.unwrap();
+        assert!(redis.get(&dedup.key(&seen)).is_none());
+        assert_eq!(dedup.check(&event, &EventType::PUSH).await.unwrap(), DedupOutcome::Unique);
+    }
+
+    #[tokio::test]
+    async fn unreadable_first_seen_is_still_duplicate() {
+        let redis = FakeRedis::start().await;
+        let dedup = Deduplicator::new(redis.pool());
+        let event = event("10", None);
+        redis.set(&dedup.key(&fingerprint(&event)), "garbage");
+        let outcome = dedup.check(&event, &EventType::SMS).await.unwrap();
+        assert!(matches!(outcome, DedupOutcome::Duplicate { first_seen: None, .. }));
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::info;
+use crate::send_modes::send_mode::SendMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Liveness {
+    Online,
+    Stale,
+    Offline,
+}
+
+#[derive(Debug, Clone)]
+pub struct LivenessConfig {
+    pub stale_multiplier: f64,
+    pub offline_multiplier: f64,
+    pub default_interval: Duration,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            stale_multiplier: 1.5,
+            offline_multiplier: 3.0,
+            default_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl LivenessConfig {
+    fn clamped(mut self) -> Self {
+        let defaults = Self::default();
+        let valid = |multiplier: f64| multiplier.is_finite() && multiplier >= 0.0;
+        if !valid(self.stale_multiplier) {
+            self.stale_multiplier = defaults.stale_multiplier;
+        }
+        if !valid(self.offline_multiplier) {
+            self.offline_multiplier = defaults.offline_multiplier;
+        }
+        self.offline_multiplier = self.offline_multiplier.max(self.stale_multiplier);
+        self
+    }
+
+    pub fn interval(&self, send_mode: &SendMode) -> Duration {
+        match send_mode.auto_heartbeat_interval {
+            Some(interval) if interval > 0 => Duration::from_secs(interval as u64),
+            _ => self.default_interval,
+        }
+    }
+
+    pub fn liveness(&self, send_mode: &SendMode, now: DateTime<Utc>) -> Liveness {
+        let silence = (now - send_mode.last_heartbeat).to_std().unwrap_or_default();
+        let interval = self.interval(send_mode);
+        if silence >= scale(interval, self.offline_multiplier) {
+            Liveness::Offline
+        } else if silence >= scale(interval, self.stale_multiplier) {
+            Liveness::Stale
+        } else {
+            Liveness::Online
+        }
+    }
+}
+
+/// `interval * multiplier`, never reached if the product is negative or does not fit.
+fn scale(interval: Duration, multiplier: f64) -> Duration {
+    Duration::try_from_secs_f64(interval.as_secs_f64() * multiplier).unwrap_or(Duration::MAX)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LivenessTransition {
+    pub send_mode_id: String,
+    pub aggregate_id: String,
+    pub from: Option<Liveness>,
+    pub to: Liveness,
+    pub last_heartbeat: DateTime<Utc>,
+}
+
+struct TrackedMode {
+    send_mode: SendMode,
+    liveness: Liveness,
+}
+
+pub struct LivenessTracker {
+    config: LivenessConfig,
+    modes: HashMap<String, TrackedMode>,
+    events: broadcast::Sender<LivenessTransition>,
+}
+
+impl Default for LivenessTracker {
+    fn default() -> Self {
+        Self::new(LivenessConfig::default())
+    }
+}
+
+impl LivenessTracker {
+    pub fn new(config: LivenessConfig) -> Self {
+        Self {
+            config: config.clamped(),
+            modes: HashMap::new(),
+            events: broadcast::channel(1024).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LivenessTransition> {
+        self.events.subscribe()
+    }
+
+    pub fn upsert(&mut self, send_mode: SendMode, now: DateTime<Utc>) -> Option<LivenessTransition> {
+        let liveness = self.config.liveness(&send_mode, now);
+        let from = self.modes.get(&send_mode.id).map(|tracked| tracked.liveness);
+        let transition = (from != Some(liveness)).then(|| LivenessTransition {
+            send_mode_id: send_mode.id.clone(),
+            aggregate_id: send_mode.aggregate_id.clone(),
+            from,
+            to: liveness,
+            last_heartbeat: send_mode.last_heartbeat,
+        });
+        self.modes.insert(send_mode.id.clone(), TrackedMode { send_mode, liveness });
+        transition.inspect(|transition| self.publish(transition))
+    }
+
+    pub fn record_heartbeat(&mut self, send_mode_id: &str, at: DateTime<Utc>, now: DateTime<Utc>) -> Option<LivenessTransition> {
+        let mut send_mode = self.modes.get(send_mode_id)?.send_mode.clone();
+        if at <= send_mode.last_heartbeat {
+            return None
+        }
+        send_mode.last_heartbeat = at;
+        self.upsert(send_mode, now)
+    }
+
+    pub fn remove(&mut self, send_mode_id: &str) -> Option<SendMode> {
+        self.modes.remove(send_mode_id).map(|tracked| tracked.send_mode)
+    }
+
+    pub fn evaluate(&mut self, now: DateTime<Utc>) -> Vec<LivenessTransition> {
+        let mut transitions = Vec::new();
+        for tracked in self.modes.values_mut() {
+            let liveness = self.config.liveness(&tracked.send_mode, now);
+            if liveness != tracked.liveness {
+                transitions.push(LivenessTransition {
+                    send_mode_id: tracked.send_mode.id.clone(),
+                    aggregate_id: tracked.send_mode.aggregate_id.clone(),
+                    from: Some(tracked.liveness),
+                    to: liveness,
+                    last_heartbeat: tracked.send_mode.last_heartbeat,
+                });
+                tracked.liveness = liveness;
+            }
+        }
+        transitions.iter().for_each(|transition| self.publish(transition));
+        transitions
+    }
+
+    pub fn liveness(&self, send_mode_id: &str) -> Option<Liveness> {
+        self.modes.get(send_mode_id).map(|tracked| tracked.liveness)
+    }
+
+    pub fn usable(&self, aggregate_id: &str, now: DateTime<Utc>) -> Vec<&SendMode> {
+        self.modes.values()
+            .filter(|tracked| tracked.send_mode.aggregate_id == aggregate_id)
+            .filter(|tracked| self.config.liveness(&tracked.send_mode, now) == Liveness::Online)
+            .map(|tracked| &tracked.send_mode)
+            .collect()
+    }
+
+    fn publish(&self, transition: &LivenessTransition) {
+        info!(id=transition.send_mode_id, from=?transition.from, to=?transition.to, "Send mode liveness changed");
+        let _ = self.events.send(transition.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use super::*;
+    use crate::send_modes::fixtures;
+
+    fn send_mode(id: &str, last_heartbeat: DateTime<Utc>) -> SendMode {
+        SendMode { auto_heartbeat_interval: Some(10), last_heartbeat, ..fixtures::send_mode(id) }
+    }
+
+    #[test]
+    fn classifies_and_reports_transitions() {
+        let now = Utc::now();
+        let mut tracker = LivenessTracker::default();
+        let mut events = tracker.subscribe();
+        tracker.upsert(send_mode("a", now), now);
+        tracker.upsert(send_mode("b", now - TimeDelta::seconds(20)), now);
+        assert_eq!(tracker.liveness("b"), Some(Liveness::Stale));
+        assert_eq!(tracker.usable("aggregate", now).len(), 1);
+
+        let later = now + TimeDelta::seconds(31);
+        let transitions = tracker.evaluate(later);
+        assert_eq!(transitions.len(), 2);
+        assert!(transitions.iter().all(|t| t.to == Liveness::Offline));
+        assert!(tracker.usable("aggregate", later).is_empty());
+
+        tracker.record_heartbeat("a", later, later);
+        assert_eq!(tracker.liveness("a"), Some(Liveness::Online));
+        let last = std::iter::from_fn(|| events.try_recv().ok()).last().unwrap();
+        assert_eq!((last.send_mode_id.as_str(), last.from, last.to), ("a", Some(Liveness::Offline), Liveness::Online));
+    }
+
+    #[test]
+    fn clamps_multipliers() {
+        let now = Utc::now();
+        let config = LivenessConfig { stale_multiplier: -1.0, offline_multiplier: f64::NAN, ..LivenessConfig::default() };
+        let mut tracker = LivenessTracker::new(config);
+        tracker.upsert(send_mode("a", now - TimeDelta::seconds(20)), now);
+        assert_eq!(tracker.liveness("a"), Some(Liveness::Stale));
+
+        let config = LivenessConfig { stale_multiplier: 4.0, offline_multiplier: 2.0, ..LivenessConfig::default() };
+        let tracker = LivenessTracker::new(config);
+        assert_eq!(tracker.config.offline_multiplier, 4.0);
+
+        let config = LivenessConfig { stale_multiplier: -1.0, offline_multiplier: f64::MAX, ..LivenessConfig::default() };
+        assert_eq!(config.liveness(&send_mode("a", now - TimeDelta::days(1)), now), Liveness::Online);
+    }
+}
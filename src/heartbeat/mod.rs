@@ -0,0 +1,16 @@
+pub mod scheduler;
+pub mod liveness;
+
+use std::future::Future;
+use crate::send_modes::error::LibError;
+use crate::tools::send_mode_client::SendModeClient;
+
+pub trait HeartbeatSender {
+    fn heartbeat(&self, send_mode_id: &str) -> impl Future<Output = Result<(), LibError>> + Send;
+}
+
+impl HeartbeatSender for SendModeClient {
+    async fn heartbeat(&self, send_mode_id: &str) -> Result<(), LibError> {
+        SendModeClient::heartbeat(self, send_mode_id).await
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_retry2::strategy::{jitter_range, ExponentialFactorBackoff};
+use tracing::{debug, info, warn};
+use crate::heartbeat::HeartbeatSender;
+use crate::send_modes::error::LibError;
+use crate::send_modes::send_mode::SendMode;
+
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    pub jitter: f64,
+    pub failure_backoff: Duration,
+    pub max_failure_backoff: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            jitter: 0.1,
+            failure_backoff: Duration::from_secs(1),
+            max_failure_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// Keeps the jitter factor non-negative, `Duration::mul_f64` panics otherwise.
+    fn clamped(mut self) -> Self {
+        self.jitter = if self.jitter.is_finite() { self.jitter.clamp(0.0, 1.0) } else { 0.0 };
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatState {
+    Pending,
+    Healthy,
+    Failing,
+    Deleted,
+    Stopped,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeartbeatHealth {
+    pub state: HeartbeatState,
+    pub interval: Duration,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+type HealthMap = Arc<RwLock<HashMap<String, HeartbeatHealth>>>;
+
+pub struct HeartbeatScheduler<S> {
+    sender: Arc<S>,
+    config: HeartbeatConfig,
+    tasks: Mutex<HashMap<String, JoinHandle<()>>>,
+    health: HealthMap,
+    shutdown: watch::Sender<bool>,
+}
+
+impl<S: HeartbeatSender + Send + Sync + 'static> HeartbeatScheduler<S> {
+    pub fn new(sender: S) -> Self {
+        Self::with_config(sender, HeartbeatConfig::default())
+    }
+
+    pub fn with_config(sender: S, config: HeartbeatConfig) -> Self {
+        Self {
+            sender: Arc::new(sender),
+            config: config.clamped(),
+            tasks: Mutex::new(HashMap::new()),
+            health: Arc::new(RwLock::new(HashMap::new())),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    pub fn register(&self, send_mode: &SendMode) -> bool {
+        match send_mode.auto_heartbeat_interval {
+            Some(interval) if interval > 0 => {
+                self.register_with_interval(&send_mode.id, Duration::from_secs(interval as u64));
+                true
+            }
+            _ => {
+                debug!(id=send_mode.id, "Send mode has no heartbeat interval");
+                false
+            }
+        }
+    }
+
+    pub fn register_with_interval(&self, send_mode_id: &str, interval: Duration) {
+        set_health(&self.health, send_mode_id, HeartbeatHealth {
+            state: HeartbeatState::Pending,
+            interval,
+            last_success: None,
+            last_error: None,
+            consecutive_failures: 0,
+        });
+        let task = tokio::spawn(run_heartbeats(
+            self.sender.clone(),
+            send_mode_id.to_owned(),
+            interval,
+            self.config.clone(),
+            self.health.clone(),
+            self.shutdown.subscribe(),
+        ));
+        if let Some(previous) = lock_tasks(&self.tasks).insert(send_mode_id.to_owned(), task) {
+            previous.abort();
+        }
+        info!(id=send_mode_id, interval=interval.as_secs(), "Heartbeat registered");
+    }
+
+    pub fn unregister(&self, send_mode_id: &str) -> bool {
+        let Some(task) = lock_tasks(&self.tasks).remove(send_mode_id) else {
+            return false
+        };
+        task.abort();
+        update_health(&self.health, send_mode_id, |health| health.state = HeartbeatState::Stopped);
+        true
+    }
+
+    pub fn registered(&self) -> Vec<String> {
+        lock_tasks(&self.tasks).iter()
+            .filter(|(_, task)| !task.is_finished())
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    pub fn health(&self, send_mode_id: &str) -> Option<HeartbeatHealth> {
+        read_health(&self.health).get(send_mode_id).cloned()
+    }
+
+    pub fn health_all(&self) -> HashMap<String, HeartbeatHealth> {
+        read_health(&self.health).clone()
+    }
+
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        let tasks: Vec<_> = lock_tasks(&self.tasks).drain().collect();
+        for (id, task) in tasks {
+            if let Err(e) = task.await && !e.is_cancelled() {
+                warn!(id=id, err=e.to_string(), "Heartbeat task failed");
+            }
+            update_health(&self.health, &id, |health| {
+                if health.state != HeartbeatState::Deleted {
+                    health.state = HeartbeatState::Stopped;
+                }
+            });
+        }
+    }
+}
+
+async fn run_heartbeats<S: HeartbeatSender>(
+    sender: Arc<S>,
+    send_mode_id: String,
+    interval: Duration,
+    config: HeartbeatConfig,
+    health: HealthMap,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let jitter = jitter_range(1.0 - config.jitter, 1.0 + config.jitter);
+    let new_backoff = || ExponentialFactorBackoff::from_millis(config.failure_backoff.as_millis() as u64, 2.0)
+        .max_delay(config.max_failure_backoff.min(interval));
+    let mut backoff = new_backoff();
+    let mut delay = jitter(interval);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.wait_for(|stop| *stop) => return,
+        }
+        match sender.heartbeat(&send_mode_id).await {
+            Ok(()) => {
+                update_health(&health, &send_mode_id, |health| {
+                    health.state = HeartbeatState::Healthy;
+                    health.last_success = Some(Utc::now());
+                    health.consecutive_failures = 0;
+                });
+                backoff = new_backoff();
+                delay = jitter(interval);
+            }
+            Err(e @ LibError::NotFound(_)) => {
+                let e = e.to_string();
+                warn!(id=send_mode_id, err=e, "Send mode is deleted, heartbeats stopped");
+                update_health(&health, &send_mode_id, |health| {
+                    health.state = HeartbeatState::Deleted;
+                    health.last_error = Some(e);
+                });
+                return
+            }
+            Err(e) => {
+                warn!(id=send_mode_id, err=e.to_string(), "Heartbeat error");
+                update_health(&health, &send_mode_id, |health| {
+                    health.state = HeartbeatState::Failing;
+                    health.last_error = Some(e.to_string());
+                    health.consecutive_failures += 1;
+                });
+                delay = jitter(backoff.next().unwrap_or(interval));
+            }
+        }
+    }
+}
+
+fn lock_tasks(tasks: &Mutex<HashMap<String, JoinHandle<()>>>) -> std::sync::MutexGuard<'_, HashMap<String, JoinHandle<()>>> {
+    tasks.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn read_health(health: &HealthMap) -> std::sync::RwLockReadGuard<'_, HashMap<String, HeartbeatHealth>> {
+    health.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn set_health(health: &HealthMap, send_mode_id: &str, value: HeartbeatHealth) {
+    health.write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(send_mode_id.to_owned(), value);
+}
+
+fn update_health(health: &HealthMap, send_mode_id: &str, update: impl FnOnce(&mut HeartbeatHealth)) {
+    let mut health = health.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(entry) = health.get_mut(send_mode_id) {
+        update(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeSender {
+        calls: AtomicUsize,
+    }
+
+    impl HeartbeatSender for FakeSender {
+        async fn heartbeat(&self, send_mode_id: &str) -> Result<(), LibError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match send_mode_id {
+                "deleted" => Err(LibError::not_found(send_mode_id)),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_heartbeats_and_stops_deleted_modes() {
+        let scheduler = HeartbeatScheduler::new(FakeSender::default());
+        scheduler.register_with_interval("alive", Duration::from_secs(10));
+        scheduler.register_with_interval("deleted", Duration::from_secs(10));
+
+        tokio::time::sleep(Duration::from_secs(35)).await;
+        assert_eq!(scheduler.health("alive").unwrap().state, HeartbeatState::Healthy);
+        assert_eq!(scheduler.health("deleted").unwrap().state, HeartbeatState::Deleted);
+        assert_eq!(scheduler.registered(), vec!["alive".to_owned()]);
+        assert_eq!(scheduler.sender.calls.load(Ordering::SeqCst), 4);
+
+        scheduler.shutdown().await;
+        assert_eq!(scheduler.health("alive").unwrap().state, HeartbeatState::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clamps_jitter() {
+        for jitter in [1.5, -0.5, f64::NAN] {
+            let config = HeartbeatConfig { jitter, ..HeartbeatConfig::default() };
+            let scheduler = HeartbeatScheduler::with_config(FakeSender::default(), config);
+            assert!((0.0..=1.0).contains(&scheduler.config.jitter));
+            scheduler.register_with_interval("alive", Duration::from_secs(10));
+
+            tokio::time::sleep(Duration::from_secs(25)).await;
+            assert_eq!(scheduler.health("alive").unwrap().state, HeartbeatState::Healthy);
+            scheduler.shutdown().await;
+        }
+    }
+}
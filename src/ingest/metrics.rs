@@ -0,0 +1,109 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Authenticate,
+    Normalize,
+    Match,
+    Dedup,
+    Persist,
+    Publish,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Authenticate,
+        Stage::Normalize,
+        Stage::Match,
+        Stage::Dedup,
+        Stage::Persist,
+        Stage::Publish,
+    ];
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Authenticate => write!(f, "authenticate"),
+            Stage::Normalize => write!(f, "normalize"),
+            Stage::Match => write!(f, "match"),
+            Stage::Dedup => write!(f, "dedup"),
+            Stage::Persist => write!(f, "persist"),
+            Stage::Publish => write!(f, "publish"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageResult {
+    Passed,
+    Rejected,
+    Failed,
+}
+
+#[derive(Debug, Default)]
+struct StageCounters {
+    passed: AtomicU64,
+    rejected: AtomicU64,
+    failed: AtomicU64,
+    total_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StageSnapshot {
+    pub passed: u64,
+    pub rejected: u64,
+    pub failed: u64,
+    pub total_time: Duration,
+}
+
+impl StageSnapshot {
+    pub fn calls(&self) -> u64 {
+        self.passed + self.rejected + self.failed
+    }
+
+    pub fn mean_time(&self) -> Option<Duration> {
+        let calls = self.calls();
+        (calls > 0).then(|| self.total_time / calls as u32)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IngestMetrics {
+    stages: [StageCounters; 6],
+}
+
+impl IngestMetrics {
+    fn counters(&self, stage: Stage) -> &StageCounters {
+        &self.stages[stage as usize]
+    }
+
+    pub fn record(&self, stage: Stage, result: StageResult, elapsed: Duration) {
+        let counters = self.counters(stage);
+        let counter = match result {
+            StageResult::Passed => &counters.passed,
+            StageResult::Rejected => &counters.rejected,
+            StageResult::Failed => &counters.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        counters.total_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, stage: Stage) -> StageSnapshot {
+        let counters = self.counters(stage);
+        StageSnapshot {
+            passed: counters.passed.load(Ordering::Relaxed),
+            rejected: counters.rejected.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+            total_time: Duration::from_micros(counters.total_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn snapshot_all(&self) -> Vec<(Stage, StageSnapshot)> {
+        Stage::ALL.iter().map(|&stage| (stage, self.snapshot(stage))).collect()
+    }
+}
@@ -0,0 +1,409 @@
+pub mod metrics;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tracing::{debug, warn};
+use crate::cache::SendModeSource;
+use crate::dedup::{DedupOutcome, Deduplicator};
+use crate::ingest::metrics::{IngestMetrics, Stage, StageResult};
+use crate::send_modes::error::{LibError, ParseError};
+use crate::send_modes::event::{Event, EventType, SendEvent, TextMessage};
+use crate::send_modes::provider::ProviderRegistry;
+use crate::send_modes::send_mode::SendMode;
+use crate::send_modes::template_set::{SharedTemplateSet, TemplateSet};
+use crate::tools::signing::Credential;
+
+pub trait Authenticate {
+    fn authenticate(&self, mode_id: &str, credential: Credential<'_>, event: &SendEvent)
+        -> impl Future<Output = Result<SendMode, LibError>> + Send;
+}
+
+pub trait Normalize {
+    fn normalize(&self, message: &mut TextMessage);
+}
+
+pub trait Match {
+    fn match_message(&self, message: &TextMessage, send_mode: &SendMode) -> Result<Event, ParseError>;
+}
+
+pub trait Dedup {
+    fn check(&self, event: &Event, event_type: &EventType) -> impl Future<Output = Result<DedupOutcome, LibError>> + Send;
+    fn forget(&self, event: &Event) -> impl Future<Output = Result<(), LibError>> + Send;
+}
+
+pub trait EventStore {
+    fn store(&self, message: &TextMessage, event: &Event) -> impl Future<Output = Result<(), LibError>> + Send;
+}
+
+pub trait EventPublisher {
+    fn publish(&self, event: &Event) -> impl Future<Output = Result<(), LibError>> + Send;
+}
+
+pub struct SourceAuthenticator<S> {
+    source: S,
+    providers: ProviderRegistry,
+}
+
+impl<S> SourceAuthenticator<S> {
+    pub fn new(source: S, providers: ProviderRegistry) -> Self {
+        Self { source, providers }
+    }
+}
+
+impl<S: SendModeSource + Sync> Authenticate for SourceAuthenticator<S> {
+    async fn authenticate(&self, mode_id: &str, credential: Credential<'_>, event: &SendEvent) -> Result<SendMode, LibError> {
+        let send_mode = self.source.get_send_mode(mode_id).await?;
+        let provider = self.providers.get(&send_mode.send_mode);
+        if provider.capabilities.needs_rsa_key && !credential.is_signature() {
+            warn!(id=mode_id, provider=provider.name(), "Request is not signed");
+            return Err(LibError::InvalidCredentials("signature is required".to_owned()))
+        }
+        credential.verify(&send_mode)?;
+        if !provider.capabilities.supports(&event.event_type) {
+            warn!(id=mode_id, provider=provider.name(), event_type=event.event_type.to_string(), "Channel is not supported");
+            return Err(LibError::InvalidDeviceMode)
+        }
+        if let Some(reason) = provider.check(&send_mode) {
+            warn!(id=mode_id, provider=provider.name(), reason=reason, "Send mode is not usable");
+            return Err(LibError::InvalidDeviceMode)
+        }
+        Ok(send_mode)
+    }
+}
+
+pub struct WhitespaceNormalizer;
+
+impl Normalize for WhitespaceNormalizer {
+    fn normalize(&self, message: &mut TextMessage) {
+        message.text = message.text.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+}
+
+pub struct TemplateMatcher<T> {
+    templates: T,
+    providers: ProviderRegistry,
+}
+
+impl<T> TemplateMatcher<T> {
+    pub fn new(templates: T, providers: ProviderRegistry) -> Self {
+        Self { templates, providers }
+    }
+}
+
+impl Match for TemplateMatcher<TemplateSet> {
+    fn match_message(&self, message: &TextMessage, send_mode: &SendMode) -> Result<Event, ParseError> {
+        self.templates.parse_for(message, &self.providers.get(&send_mode.send_mode))
+    }
+}
+
+impl Match for TemplateMatcher<SharedTemplateSet> {
+    fn match_message(&self, message: &TextMessage, send_mode: &SendMode) -> Result<Event, ParseError> {
+        self.templates.load().parse_for(message, &self.providers.get(&send_mode.send_mode))
+    }
+}
+
+impl Dedup for Deduplicator {
+    async fn check(&self, event: &Event, event_type: &EventType) -> Result<DedupOutcome, LibError> {
+        Deduplicator::check(self, event, event_type).await
+    }
+
+    async fn forget(&self, event: &Event) -> Result<(), LibError> {
+        Deduplicator::forget(self, event).await
+    }
+}
+
+pub struct NoDedup;
+
+impl Dedup for NoDedup {
+    async fn check(&self, _event: &Event, _event_type: &EventType) -> Result<DedupOutcome, LibError> {
+        Ok(DedupOutcome::Unique)
+    }
+
+    async fn forget(&self, _event: &Event) -> Result<(), LibError> {
+        Ok(())
+    }
+}
+
+pub struct NoPublish;
+
+impl EventPublisher for NoPublish {
+    async fn publish(&self, _event: &Event) -> Result<(), LibError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Ingest {stage} stage failed: {error}")]
+pub struct IngestError {
+    pub stage: Stage,
+    pub error: LibError,
+}
+
+#[derive(Debug)]
+pub enum IngestOutcome {
+    Accepted(Event),
+    Duplicate { event: Event, dedup: DedupOutcome },
+    Unmatched { message: TextMessage, error: ParseError },
+}
+
+/// After a publish failure the redelivery stores the event again, the store must be idempotent
+/// unless it publishes itself like [`EventOutbox`](crate::repository::outbox::EventOutbox).
+pub struct IngestPipeline<A, M, D, S, P> {
+    authenticator: A,
+    normalizers: Vec<Box<dyn Normalize + Send + Sync>>,
+    matcher: M,
+    dedup: D,
+    store: S,
+    publisher: P,
+    metrics: Arc<IngestMetrics>,
+}
+
+impl<A, M, D, S, P> IngestPipeline<A, M, D, S, P>
+where
+    A: Authenticate,
+    M: Match,
+    D: Dedup,
+    S: EventStore,
+    P: EventPublisher,
+{
+    pub fn new(authenticator: A, matcher: M, dedup: D, store: S, publisher: P) -> Self {
+        Self {
+            authenticator,
+            normalizers: vec![Box::new(WhitespaceNormalizer)],
+            matcher,
+            dedup,
+            store,
+            publisher,
+            metrics: Arc::new(IngestMetrics::default()),
+        }
+    }
+
+    pub fn with_normalizer(mut self, normalizer: impl Normalize + Send + Sync + 'static) -> Self {
+        self.normalizers.push(Box::new(normalizer));
+        self
+    }
+
+    pub fn without_normalizers(mut self) -> Self {
+        self.normalizers.clear();
+        self
+    }
+
+    pub fn metrics(&self) -> Arc<IngestMetrics> {
+        self.metrics.clone()
+    }
+
+    pub async fn ingest(&self, mode_id: &str, credential: Credential<'_>, event: SendEvent) -> Result<IngestOutcome, IngestError> {
+        let started = Instant::now();
+        let send_mode = self.authenticator.authenticate(mode_id, credential, &event).await
+            .map_err(|e| self.failed(Stage::Authenticate, started, e))?;
+        self.metrics.record(Stage::Authenticate, StageResult::Passed, started.elapsed());
+
+        let started = Instant::now();
+        let mut message = TextMessage::new(send_mode.id.clone(), event);
+        for normalizer in &self.normalizers {
+            normalizer.normalize(&mut message);
+        }
+        self.metrics.record(Stage::Normalize, StageResult::Passed, started.elapsed());
+
+        let started = Instant::now();
+        let parsed = match self.matcher.match_message(&message, &send_mode) {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                self.metrics.record(Stage::Match, StageResult::Rejected, started.elapsed());
+                debug!(mode_id=message.mode_id, err=error.to_string(), "Message not matched");
+                return Ok(IngestOutcome::Unmatched { message, error })
+            }
+        };
+        self.metrics.record(Stage::Match, StageResult::Passed, started.elapsed());
+
+        let started = Instant::now();
+        let dedup = self.dedup.check(&parsed, &message.event_type).await
+            .map_err(|e| self.failed(Stage::Dedup, started, e))?;
+        if dedup.is_duplicate() {
+            self.metrics.record(Stage::Dedup, StageResult::Rejected, started.elapsed());
+            return Ok(IngestOutcome::Duplicate { event: parsed, dedup })
+        }
+        self.metrics.record(Stage::Dedup, StageResult::Passed, started.elapsed());
+
+        let started = Instant::now();
+        if let Err(e) = self.store.store(&message, &parsed).await {
+            self.release(&parsed).await;
+            return Err(self.failed(Stage::Persist, started, e))
+        }
+        self.metrics.record(Stage::Persist, StageResult::Passed, started.elapsed());
+
+        let started = Instant::now();
+        if let Err(e) = self.publisher.publish(&parsed).await {
+            self.release(&parsed).await;
+            return Err(self.failed(Stage::Publish, started, e))
+        }
+        self.metrics.record(Stage::Publish, StageResult::Passed, started.elapsed());
+        Ok(IngestOutcome::Accepted(parsed))
+    }
+
+    fn failed(&self, stage: Stage, started: Instant, error: LibError) -> IngestError {
+        self.metrics.record(stage, StageResult::Failed, started.elapsed());
+        warn!(stage=stage.to_string(), err=error.to_string(), "Ingest stage failed");
+        IngestError { stage, error }
+    }
+
+    async fn release(&self, event: &Event) {
+        if let Err(e) = self.dedup.forget(event).await {
+            warn!(mode_id=event.mode_id, err=e.to_string(), "Dedup release error");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use super::*;
+    use crate::send_modes::fixtures;
+    use crate::send_modes::provider::{Provider, ProviderCapabilities};
+    use crate::send_modes::send_mode::SendModeEnum;
+
+    struct Modes;
+
+    impl Authenticate for Modes {
+        async fn authenticate(&self, mode_id: &str, _credential: Credential<'_>, _event: &SendEvent) -> Result<SendMode, LibError> {
+            if mode_id != "mode" {
+                return Err(LibError::not_found(mode_id))
+            }
+            Ok(fixtures::send_mode(mode_id))
+        }
+    }
+
+    struct Source;
+
+    impl SendModeSource for Source {
+        async fn get_send_mode(&self, id: &str) -> Result<SendMode, LibError> {
+            Ok(fixtures::send_mode(id))
+        }
+
+        async fn delete_send_mode(&self, _id: &str) -> Result<(), LibError> {
+            Ok(())
+        }
+    }
+
+    const TOKEN: Credential<'static> = Credential::AccessToken("token");
+
+    #[derive(Default)]
+    struct Stored(Mutex<Vec<Event>>);
+
+    impl EventStore for &Stored {
+        async fn store(&self, _message: &TextMessage, event: &Event) -> Result<(), LibError> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Claims(Mutex<Vec<Event>>);
+
+    impl Dedup for &Claims {
+        async fn check(&self, _event: &Event, _event_type: &EventType) -> Result<DedupOutcome, LibError> {
+            Ok(DedupOutcome::Unique)
+        }
+
+        async fn forget(&self, event: &Event) -> Result<(), LibError> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    struct FailingPublisher;
+
+    impl EventPublisher for FailingPublisher {
+        async fn publish(&self, _event: &Event) -> Result<(), LibError> {
+            Err(LibError::RedisError("publish failed".to_owned()))
+        }
+    }
+
+    fn templates() -> TemplateMatcher<TemplateSet> {
+        TemplateMatcher::new(template_set(), ProviderRegistry::default())
+    }
+
+    fn template_set() -> TemplateSet {
+        TemplateSet::new(vec![fixtures::template("Зачисление {amount}р")])
+    }
+
+    fn send_event(text: &str) -> SendEvent {
+        SendEvent {
+            source: "900".to_owned(),
+            text: text.to_owned(),
+            event_type: EventType::SMS,
+            subject: None,
+            sender: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_stages_and_records_metrics() {
+        let stored = Stored::default();
+        let pipeline = IngestPipeline::new(Modes, templates(), NoDedup, &stored, NoPublish);
+
+        let outcome = pipeline.ingest("mode", TOKEN, send_event("  Зачисление\u{a0}10р ")).await.unwrap();
+        assert!(matches!(outcome, IngestOutcome::Accepted(_)));
+        assert!(matches!(pipeline.ingest("mode", TOKEN, send_event("Списание 10р")).await, Ok(IngestOutcome::Unmatched { .. })));
+        let err = pipeline.ingest("other", TOKEN, send_event("Зачисление 10р")).await.unwrap_err();
+        assert_eq!(err.stage, Stage::Authenticate);
+
+        assert_eq!(stored.0.lock().unwrap().len(), 1);
+        let metrics = pipeline.metrics();
+        assert_eq!(metrics.snapshot(Stage::Authenticate).failed, 1);
+        assert_eq!(metrics.snapshot(Stage::Match).rejected, 1);
+        assert_eq!(metrics.snapshot(Stage::Publish).passed, 1);
+    }
+
+    #[tokio::test]
+    async fn releases_claim_when_publish_fails() {
+        let stored = Stored::default();
+        let claims = Claims::default();
+        let pipeline = IngestPipeline::new(Modes, templates(), &claims, &stored, FailingPublisher);
+        let err = pipeline.ingest("mode", TOKEN, send_event("Зачисление 10р")).await.unwrap_err();
+        assert_eq!(err.stage, Stage::Publish);
+        assert_eq!(claims.0.lock().unwrap().len(), 1);
+        assert_eq!(pipeline.metrics().snapshot(Stage::Publish).failed, 1);
+    }
+
+    #[tokio::test]
+    async fn source_authenticator_verifies_credential() {
+        let event = send_event("Зачисление 10р");
+        let authenticator = SourceAuthenticator::new(Source, ProviderRegistry::empty());
+        assert_eq!(authenticator.authenticate("mode", TOKEN, &event).await.unwrap().id, "mode");
+        let wrong = authenticator.authenticate("mode", Credential::AccessToken("other"), &event).await;
+        assert!(matches!(wrong, Err(LibError::InvalidCredentials(_))));
+
+        let authenticator = SourceAuthenticator::new(Source, ProviderRegistry::default());
+        assert!(authenticator.authenticate("mode", TOKEN, &event).await.is_ok());
+
+        // A provider registered as signing its requests does not accept a token alone
+        let signed = ProviderCapabilities { needs_rsa_key: true, ..ProviderCapabilities::permissive() };
+        let registry = ProviderRegistry::default().with(Provider::new(SendModeEnum::KRAFT, signed));
+        let authenticator = SourceAuthenticator::new(Source, registry);
+        let unsigned = authenticator.authenticate("mode", TOKEN, &event).await;
+        assert!(matches!(unsigned, Err(LibError::InvalidCredentials(_))));
+    }
+
+    #[test]
+    fn matches_in_provider_namespace() {
+        let payflow: SendModeEnum = serde_json::from_str(r#""PAYFLOW""#).unwrap();
+        let message = TextMessage {
+            mode_id: "mode".to_owned(),
+            source: "900".to_owned(),
+            text: "Зачисление 10р".to_owned(),
+            event_type: EventType::SMS,
+            subject: None,
+            sender: None,
+        };
+        let send_mode = SendMode { send_mode: payflow.clone(), ..fixtures::send_mode("mode") };
+        assert!(templates().match_message(&message, &send_mode).is_err());
+
+        let provider = Provider::new(payflow, ProviderCapabilities::permissive()).with_template_namespace(SendModeEnum::KRAFT);
+        let shared = SharedTemplateSet::new(template_set());
+        let matcher = TemplateMatcher::new(shared, ProviderRegistry::default().with(provider));
+        assert_eq!(matcher.match_message(&message, &send_mode).unwrap().bank, "sber");
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use tokio::sync::broadcast;
+use tracing::warn;
+use crate::send_modes::event::{Event, TransactionKind};
+use crate::send_modes::money::{Currency, Money};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AccountKey {
+    mode_id: String,
+    bank: String,
+    currency: Currency,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceGap {
+    pub mode_id: String,
+    pub bank: String,
+    pub previous_balance: Money,
+    pub expected: Money,
+    pub actual: Money,
+    pub missing_delta: Money,
+    pub reconstructed: Event,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Continuity {
+    First,
+    Consistent,
+    Gap(Box<BalanceGap>),
+    Skipped,
+}
+
+pub fn balance_change(event: &Event) -> Decimal {
+    match event.kind {
+        TransactionKind::Credit | TransactionKind::Refund => event.amount.amount,
+        TransactionKind::Debit => -event.amount.amount,
+        TransactionKind::Failed | TransactionKind::BalanceOnly => Decimal::ZERO,
+    }
+}
+
+/// Events must be recorded in order and after deduplication, otherwise repeats show up as gaps.
+pub struct BalanceLedger {
+    balances: HashMap<AccountKey, Money>,
+    gaps: broadcast::Sender<BalanceGap>,
+}
+
+impl Default for BalanceLedger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BalanceLedger {
+    pub fn new() -> Self {
+        Self {
+            balances: HashMap::new(),
+            gaps: broadcast::channel(1024).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BalanceGap> {
+        self.gaps.subscribe()
+    }
+
+    pub fn record(&mut self, event: &Event) -> Continuity {
+        let Some(actual) = event.balance else {
+            return Continuity::Skipped
+        };
+        let key = AccountKey {
+            mode_id: event.mode_id.clone(),
+            bank: event.bank.clone(),
+            currency: actual.currency,
+        };
+        let Some(previous_balance) = self.balances.insert(key, actual) else {
+            return Continuity::First
+        };
+        let expected = Money::new(previous_balance.amount + balance_change(event), actual.currency);
+        if expected.amount == actual.amount {
+            return Continuity::Consistent
+        }
+        let delta = actual.amount - expected.amount;
+        let gap = BalanceGap {
+            mode_id: event.mode_id.clone(),
+            bank: event.bank.clone(),
+            previous_balance,
+            expected,
+            actual,
+            missing_delta: Money::new(delta, actual.currency),
+            reconstructed: Event {
+                mode_id: event.mode_id.clone(),
+                bank: event.bank.clone(),
+                amount: Money::new(delta.abs(), actual.currency),
+                requisite: None,
+                balance: Some(Money::new(previous_balance.amount + delta, actual.currency)),
+                search_by: event.search_by.clone(),
+                kind: if delta > Decimal::ZERO { TransactionKind::Credit } else { TransactionKind::Debit },
+                counterparty: None,
+                card: None,
+                timestamp: None,
+            },
+        };
+        warn!(mode_id=gap.mode_id, bank=gap.bank, delta=gap.missing_delta.to_string(), "Missed notification, balance gap");
+        let _ = self.gaps.send(gap.clone());
+        Continuity::Gap(Box::new(gap))
+    }
+
+    pub fn balance(&self, mode_id: &str, bank: &str, currency: Currency) -> Option<Money> {
+        let key = AccountKey { mode_id: mode_id.to_owned(), bank: bank.to_owned(), currency };
+        self.balances.get(&key).copied()
+    }
+
+    pub fn reset(&mut self, mode_id: &str) {
+        self.balances.retain(|key, _| key.mode_id != mode_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: TransactionKind, amount: i64, balance: i64) -> Event {
+        Event {
+            mode_id: "mode".to_owned(),
+            bank: "sber".to_owned(),
+            amount: Money::new(Decimal::from(amount), Currency::RUB),
+            requisite: None,
+            balance: Some(Money::new(Decimal::from(balance), Currency::RUB)),
+            search_by: "amount".to_owned(),
+            kind,
+            counterparty: None,
+            card: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn detects_missed_credit() {
+        let mut ledger = BalanceLedger::new();
+        assert_eq!(ledger.record(&event(TransactionKind::Credit, 100, 1000)), Continuity::First);
+        assert_eq!(ledger.record(&event(TransactionKind::Debit, 50, 950)), Continuity::Consistent);
+        let Continuity::Gap(gap) = ledger.record(&event(TransactionKind::Credit, 10, 1160)) else {
+            panic!("gap expected")
+        };
+        assert_eq!(gap.missing_delta.amount, Decimal::from(200));
+        assert_eq!(gap.reconstructed.kind, TransactionKind::Credit);
+        assert_eq!(gap.reconstructed.balance.unwrap().amount, Decimal::from(1150));
+        assert_eq!(ledger.balance("mode", "sber", Currency::RUB).unwrap().amount, Decimal::from(1160));
+    }
+}
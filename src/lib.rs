@@ -1,5 +1,12 @@
 pub mod send_modes;
 pub mod tools;
+pub mod repository;
+pub mod cache;
+pub mod heartbeat;
+pub mod dedup;
+pub mod ledger;
+pub mod ingest;
+pub mod streams;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
@@ -0,0 +1,34 @@
+pub mod send_mode_repository;
+pub mod template_repository;
+pub mod template_listener;
+pub mod outbox;
+
+use deadpool_postgres::{Object, Pool};
+use tracing::error;
+use crate::send_modes::error::LibError;
+
+pub const DEFAULT_MAX_RETRIES: usize = 3;
+
+pub(crate) async fn get_connection(pool: &Pool) -> Result<Object, LibError> {
+    pool.get().await.map_err(|e| {
+        error!(err=e.to_string(), "Get postgres connection error");
+        LibError::DatabaseError(e.to_string())
+    })
+}
+
+/// Database tests are ignored by default, run them with `cargo test -- --ignored`.
+#[cfg(test)]
+pub(crate) async fn test_pool(schema: &str) -> (Pool, deadpool_postgres::tokio_postgres::Config) {
+    use deadpool_postgres::tokio_postgres::{Config, NoTls};
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run database tests");
+    let mut config: Config = url.parse().unwrap();
+    let name = format!("test_{}", uuid::Uuid::new_v4().simple());
+    let (client, connection) = config.connect(NoTls).await.unwrap();
+    tokio::spawn(connection);
+    client.batch_execute(&format!("CREATE SCHEMA {name}")).await.unwrap();
+    config.options(format!("-c search_path={name}"));
+    let manager = deadpool_postgres::Manager::new(config.clone(), NoTls);
+    let pool = Pool::builder(manager).max_size(4).build().unwrap();
+    get_connection(&pool).await.unwrap().batch_execute(schema).await.unwrap();
+    (pool, config)
+}
@@ -0,0 +1,413 @@
+use std::sync::Arc;
+use std::time::Duration;
+use deadpool_postgres::{GenericClient, Pool};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+use crate::ingest::{EventPublisher, EventStore};
+use crate::repository::get_connection;
+use crate::send_modes::error::LibError;
+use crate::send_modes::event::{Event, TextMessage};
+
+pub const CREATE_OUTBOX_TABLES: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id BIGSERIAL PRIMARY KEY,
+    mode_id VARCHAR NOT NULL,
+    bank VARCHAR NOT NULL,
+    kind VARCHAR NOT NULL,
+    amount NUMERIC NOT NULL,
+    currency VARCHAR NOT NULL,
+    source VARCHAR NOT NULL,
+    event_type VARCHAR NOT NULL,
+    text TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS events_mode_id_idx ON events (mode_id, created_at);
+CREATE TABLE IF NOT EXISTS event_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    event_id BIGINT NOT NULL REFERENCES events (id),
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    published_at TIMESTAMPTZ,
+    dead_at TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS event_outbox_pending_idx ON event_outbox (next_attempt_at) WHERE published_at IS NULL;";
+
+fn db_error(e: String) -> LibError {
+    error!(err=e, "Outbox error");
+    LibError::DatabaseError(e)
+}
+
+pub struct EventOutbox {
+    pool: Pool,
+}
+
+impl EventOutbox {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn insert(&self, message: &TextMessage, event: &Event) -> Result<i64, LibError> {
+        let payload = serde_json::to_string(event).map_err(|e| {
+            error!(err=e.to_string(), "Event serialize error");
+            LibError::InternalServerError
+        })?;
+        let mut client = get_connection(&self.pool).await?;
+        let tx = client.transaction().await.map_err(|e| db_error(e.to_string()))?;
+        let row = tx.query_one(
+            "INSERT INTO events (mode_id, bank, kind, amount, currency, source, event_type, text, payload) \
+            VALUES ($1, $2, $3, $4::TEXT::NUMERIC, $5, $6, $7, $8, $9) RETURNING id",
+            &[
+                &event.mode_id,
+                &event.bank,
+                &event.kind,
+                &event.amount.amount.to_string(),
+                &event.amount.currency,
+                &message.source,
+                &message.event_type.to_string(),
+                &message.text,
+                &payload,
+            ],
+        ).await.map_err(|e| db_error(e.to_string()))?;
+        let event_id: i64 = row.get("id");
+        tx.execute("INSERT INTO event_outbox (event_id, payload) VALUES ($1, $2)", &[&event_id, &payload])
+            .await
+            .map_err(|e| db_error(e.to_string()))?;
+        tx.commit().await.map_err(|e| db_error(e.to_string()))?;
+        Ok(event_id)
+    }
+}
+
+impl EventStore for EventOutbox {
+    async fn store(&self, message: &TextMessage, event: &Event) -> Result<(), LibError> {
+        self.insert(message, event).await.map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    pub batch: i64,
+    pub poll_interval: Duration,
+    pub initial_backoff: Duration,
+    pub multiplier: f64,
+    pub max_backoff: Duration,
+    pub max_attempts: i32,
+    pub publish_timeout: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            batch: 100,
+            poll_interval: Duration::from_secs(1),
+            initial_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(300),
+            max_attempts: 20,
+            publish_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn backoff(&self, attempts: i32) -> Duration {
+        let factor = self.multiplier.powi(attempts.saturating_sub(1).max(0));
+        // Capped in f64, the uncapped delay can overflow Duration
+        Duration::from_secs_f64((self.initial_backoff.as_secs_f64() * factor).min(self.max_backoff.as_secs_f64()))
+    }
+}
+
+async fn mark_dead(client: &impl GenericClient, id: i64, attempts: i32, reason: &str) -> Result<(), LibError> {
+    error!(id=id, attempts=attempts, err=reason, "Outbox row is dead");
+    client.execute(
+        "UPDATE event_outbox SET attempts = $2, last_error = $3, dead_at = now() WHERE id = $1",
+        &[&id, &attempts, &reason],
+    ).await.map_err(|e| db_error(e.to_string()))?;
+    Ok(())
+}
+
+/// Delivery is at least once, consumers must be idempotent.
+pub struct OutboxRelay<P> {
+    pool: Pool,
+    publisher: Arc<P>,
+    config: RelayConfig,
+}
+
+pub struct OutboxRelayHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl OutboxRelayHandle {
+    pub async fn shutdown(self) {
+        self.shutdown.send_replace(true);
+        if let Err(e) = self.task.await && !e.is_cancelled() {
+            error!(err=e.to_string(), "Outbox relay task failed");
+        }
+    }
+}
+
+impl<P: EventPublisher + Send + Sync + 'static> OutboxRelay<P> {
+    pub fn new(pool: Pool, publisher: P) -> Self {
+        Self::with_config(pool, publisher, RelayConfig::default())
+    }
+
+    pub fn with_config(pool: Pool, publisher: P, config: RelayConfig) -> Self {
+        Self { pool, publisher: Arc::new(publisher), config }
+    }
+
+    pub async fn relay_batch(&self) -> Result<usize, LibError> {
+        let mut handled = 0;
+        // Rows retried with no backoff are left to the next batch
+        let mut last_id = 0;
+        while handled < self.config.batch {
+            let Some(id) = self.relay_next(last_id).await? else {
+                break
+            };
+            last_id = id;
+            handled += 1;
+        }
+        if handled > 0 {
+            debug!(rows=handled, "Outbox batch relayed");
+        }
+        Ok(handled as usize)
+    }
+
+    async fn relay_next(&self, last_id: i64) -> Result<Option<i64>, LibError> {
+        let mut client = get_connection(&self.pool).await?;
+        let tx = client.transaction().await.map_err(|e| db_error(e.to_string()))?;
+        let row = tx.query_opt(
+            "SELECT id, payload, attempts FROM event_outbox \
+            WHERE id > $1 AND published_at IS NULL AND dead_at IS NULL AND next_attempt_at <= now() \
+            ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED",
+            &[&last_id],
+        ).await.map_err(|e| db_error(e.to_string()))?;
+        let Some(row) = row else {
+            return Ok(None)
+        };
+
+        let id: i64 = row.get("id");
+        let attempts: i32 = row.get::<_, i32>("attempts") + 1;
+        let payload: String = row.get("payload");
+        match serde_json::from_str::<Event>(&payload) {
+            // Publishing again cannot fix the payload
+            Err(e) => mark_dead(&tx, id, attempts, &format!("invalid outbox payload: {e}")).await?,
+            Ok(event) => {
+                let published = tokio::time::timeout(self.config.publish_timeout, self.publisher.publish(&event)).await
+                    .unwrap_or(Err(LibError::TimeOut));
+                match published {
+                    Ok(()) => {
+                        tx.execute(
+                            "UPDATE event_outbox SET published_at = now(), attempts = $2, last_error = NULL WHERE id = $1",
+                            &[&id, &attempts],
+                        ).await.map_err(|e| db_error(e.to_string()))?;
+                    }
+                    Err(e) if attempts >= self.config.max_attempts => mark_dead(&tx, id, attempts, &e.to_string()).await?,
+                    Err(e) => {
+                        let backoff = self.config.backoff(attempts);
+                        warn!(id=id, attempts=attempts, backoff=backoff.as_secs_f64(), err=e.to_string(), "Outbox publish error");
+                        tx.execute(
+                            "UPDATE event_outbox SET attempts = $2, last_error = $3, \
+                            next_attempt_at = now() + make_interval(secs => $4) WHERE id = $1",
+                            &[&id, &attempts, &e.to_string(), &backoff.as_secs_f64()],
+                        ).await.map_err(|e| db_error(e.to_string()))?;
+                    }
+                }
+            }
+        }
+        tx.commit().await.map_err(|e| db_error(e.to_string()))?;
+        Ok(Some(id))
+    }
+
+    pub fn spawn(self) -> OutboxRelayHandle {
+        let (shutdown, receiver) = watch::channel(false);
+        let task = tokio::spawn(self.run(receiver));
+        OutboxRelayHandle { shutdown, task }
+    }
+
+    async fn run(self, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            let delay = match self.relay_batch().await {
+                // A full batch means more rows are probably due
+                Ok(handled) if handled as i64 >= self.config.batch => Duration::ZERO,
+                Ok(_) => self.config.poll_interval,
+                Err(_) => self.config.poll_interval.max(self.config.initial_backoff),
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.wait_for(|stop| *stop) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use rust_decimal::Decimal;
+    use super::*;
+    use crate::repository::test_pool;
+    use crate::send_modes::event::{EventType, TransactionKind};
+    use crate::send_modes::money::{Currency, Money};
+
+    #[derive(Default)]
+    struct Publisher {
+        published: Mutex<Vec<Event>>,
+        fail: bool,
+        hang: bool,
+    }
+
+    impl EventPublisher for Publisher {
+        async fn publish(&self, event: &Event) -> Result<(), LibError> {
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+            if self.fail {
+                return Err(LibError::RedisError("unavailable".to_owned()))
+            }
+            self.published.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    fn event() -> (TextMessage, Event) {
+        let message = TextMessage {
+            mode_id: "mode".to_owned(),
+            source: "900".to_owned(),
+            text: "Зачисление 10р".to_owned(),
+            event_type: EventType::SMS,
+            subject: None,
+            sender: None,
+        };
+        let event = Event {
+            mode_id: "mode".to_owned(),
+            bank: "sber".to_owned(),
+            amount: Money::new(Decimal::from_str("10").unwrap(), Currency::RUB),
+            requisite: None,
+            balance: None,
+            search_by: "amount".to_owned(),
+            kind: TransactionKind::Credit,
+            counterparty: None,
+            card: None,
+            timestamp: None,
+        };
+        (message, event)
+    }
+
+    fn config() -> RelayConfig {
+        RelayConfig {
+            initial_backoff: Duration::ZERO,
+            max_attempts: 2,
+            publish_timeout: Duration::from_millis(50),
+            ..RelayConfig::default()
+        }
+    }
+
+    async fn row(pool: &Pool, event_id: i64) -> (i32, bool, bool, Option<String>) {
+        let client = get_connection(pool).await.unwrap();
+        let row = client.query_one(
+            "SELECT attempts, published_at IS NOT NULL AS published, dead_at IS NOT NULL AS dead, last_error \
+            FROM event_outbox WHERE event_id = $1",
+            &[&event_id],
+        ).await.unwrap();
+        (row.get("attempts"), row.get("published"), row.get("dead"), row.get("last_error"))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn publishes_once() {
+        let (pool, _) = test_pool(CREATE_OUTBOX_TABLES).await;
+        let (message, event) = event();
+        let event_id = EventOutbox::new(pool.clone()).insert(&message, &event).await.unwrap();
+        let relay = OutboxRelay::with_config(pool.clone(), Publisher::default(), config());
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+        assert_eq!(relay.publisher.published.lock().unwrap().as_slice(), &[event]);
+        assert_eq!(row(&pool, event_id).await, (1, true, false, None));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn commits_each_row_and_skips_locked_ones() {
+        let (pool, _) = test_pool(CREATE_OUTBOX_TABLES).await;
+        let (message, event) = event();
+        let outbox = EventOutbox::new(pool.clone());
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(outbox.insert(&message, &event).await.unwrap());
+        }
+        let mut locker = get_connection(&pool).await.unwrap();
+        let lock = locker.transaction().await.unwrap();
+        lock.execute("SELECT id FROM event_outbox WHERE event_id = $1 FOR UPDATE", &[&ids[0]]).await.unwrap();
+
+        let relay = OutboxRelay::with_config(pool.clone(), Publisher::default(), RelayConfig { batch: 1, ..config() });
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        // The locked first row is skipped, not waited for
+        assert!(row(&pool, ids[1]).await.1);
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+        assert!(!row(&pool, ids[0]).await.1);
+
+        lock.rollback().await.unwrap();
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        assert_eq!(relay.publisher.published.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn marks_row_dead_after_max_attempts() {
+        let (pool, _) = test_pool(CREATE_OUTBOX_TABLES).await;
+        let (message, event) = event();
+        let event_id = EventOutbox::new(pool.clone()).insert(&message, &event).await.unwrap();
+        let relay = OutboxRelay::with_config(pool.clone(), Publisher { fail: true, ..Publisher::default() }, config());
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        assert_eq!(row(&pool, event_id).await.0, 1);
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        let (attempts, published, dead, last_error) = row(&pool, event_id).await;
+        assert_eq!((attempts, published, dead), (2, false, true));
+        assert!(last_error.unwrap().contains("unavailable"));
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn marks_invalid_payload_dead_at_once() {
+        let (pool, _) = test_pool(CREATE_OUTBOX_TABLES).await;
+        let (message, event) = event();
+        let event_id = EventOutbox::new(pool.clone()).insert(&message, &event).await.unwrap();
+        get_connection(&pool).await.unwrap()
+            .execute("UPDATE event_outbox SET payload = '{' WHERE event_id = $1", &[&event_id]).await.unwrap();
+        let relay = OutboxRelay::with_config(pool.clone(), Publisher::default(), config());
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        let (attempts, _, dead, last_error) = row(&pool, event_id).await;
+        assert_eq!((attempts, dead), (1, true));
+        assert!(last_error.unwrap().starts_with("invalid outbox payload"));
+        assert!(relay.publisher.published.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn times_out_hanging_publish() {
+        let (pool, _) = test_pool(CREATE_OUTBOX_TABLES).await;
+        let (message, event) = event();
+        let event_id = EventOutbox::new(pool.clone()).insert(&message, &event).await.unwrap();
+        let relay = OutboxRelay::with_config(pool.clone(), Publisher { hang: true, ..Publisher::default() }, config());
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        let (attempts, published, dead, last_error) = row(&pool, event_id).await;
+        assert_eq!((attempts, published, dead), (1, false, false));
+        assert_eq!(last_error.as_deref(), Some("request timeout"));
+    }
+
+    #[test]
+    fn backoff_grows_to_max() {
+        let config = RelayConfig::default();
+        assert_eq!(config.backoff(1), Duration::from_secs(1));
+        assert_eq!(config.backoff(3), Duration::from_secs(4));
+        assert_eq!(config.backoff(100), config.max_backoff);
+    }
+}
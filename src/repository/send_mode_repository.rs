@@ -0,0 +1,185 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use rsa::pkcs1::EncodeRsaPrivateKey;
+use tracing::{error, warn};
+use crate::retry;
+use crate::repository::{get_connection, DEFAULT_MAX_RETRIES};
+use crate::send_modes::error::LibError;
+use crate::send_modes::send_mode::{RenameSendModeRequest, SendMode};
+use crate::tools::is_connection_err;
+
+pub const CREATE_SEND_MODES_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS send_modes (
+    id VARCHAR PRIMARY KEY,
+    aggregate_id VARCHAR NOT NULL,
+    name VARCHAR NOT NULL,
+    send_mode VARCHAR NOT NULL,
+    access_token VARCHAR NOT NULL,
+    fingerprint VARCHAR,
+    private_key TEXT NOT NULL DEFAULT '',
+    auto_heartbeat_interval INTEGER,
+    last_heartbeat TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS send_modes_aggregate_id_idx ON send_modes (aggregate_id);";
+
+const COLUMNS: &str = "id, aggregate_id, name, send_mode, access_token, fingerprint, private_key, \
+    auto_heartbeat_interval, last_heartbeat";
+
+pub struct SendModeRepository {
+    pool: Pool,
+    max_retries: usize,
+}
+
+impl SendModeRepository {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool, max_retries: DEFAULT_MAX_RETRIES }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub async fn insert(&self, send_mode: &SendMode) -> Result<SendMode, LibError> {
+        // Missing key is stored as empty string, reading it back gives None
+        let private_key = match &send_mode.private_key {
+            Some(key) => key.to_pkcs1_pem(rsa::pkcs1::LineEnding::LF)
+                .map_err(|e| {
+                    error!(err=e.to_string(), "Private key encode error");
+                    LibError::InternalServerError
+                })?
+                .to_string(),
+            None => String::new(),
+        };
+        let client = get_connection(&self.pool).await?;
+        let sql = format!("INSERT INTO send_modes ({COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING {COLUMNS}");
+        let stmt = retry!(client.prepare_cached(&sql), self.max_retries).map_err(Self::db_error)?;
+        // Not retried, an insert that reached the database before the error would fail as a duplicate
+        let row = client.query_one(&stmt, &[
+            &send_mode.id,
+            &send_mode.aggregate_id,
+            &send_mode.name,
+            &send_mode.send_mode,
+            &send_mode.access_token,
+            &send_mode.fingerprint,
+            &private_key,
+            &send_mode.auto_heartbeat_interval,
+            &send_mode.last_heartbeat,
+        ]).await.map_err(|e| Self::db_error(e.to_string()))?;
+        Ok(SendMode::from(row))
+    }
+
+    pub async fn get_by_id(&self, id: &str) -> Result<SendMode, LibError> {
+        let client = get_connection(&self.pool).await?;
+        let sql = format!("SELECT {COLUMNS} FROM send_modes WHERE id = $1");
+        let stmt = retry!(client.prepare_cached(&sql), self.max_retries).map_err(Self::db_error)?;
+        let row = retry!(client.query_opt(&stmt, &[&id]), self.max_retries).map_err(Self::db_error)?;
+        row.map(SendMode::from).ok_or_else(|| LibError::not_found(id))
+    }
+
+    pub async fn list_by_aggregate_id(&self, aggregate_id: &str) -> Result<Vec<SendMode>, LibError> {
+        let client = get_connection(&self.pool).await?;
+        let sql = format!("SELECT {COLUMNS} FROM send_modes WHERE aggregate_id = $1 ORDER BY name");
+        let stmt = retry!(client.prepare_cached(&sql), self.max_retries).map_err(Self::db_error)?;
+        let rows = retry!(client.query(&stmt, &[&aggregate_id]), self.max_retries).map_err(Self::db_error)?;
+        Ok(rows.into_iter().map(SendMode::from).collect())
+    }
+
+    pub async fn rename(&self, request: &RenameSendModeRequest) -> Result<SendMode, LibError> {
+        let client = get_connection(&self.pool).await?;
+        let sql = format!("UPDATE send_modes SET name = $2 WHERE id = $1 RETURNING {COLUMNS}");
+        let stmt = retry!(client.prepare_cached(&sql), self.max_retries).map_err(Self::db_error)?;
+        let row = retry!(client.query_opt(&stmt, &[&request.id, &request.name]), self.max_retries)
+            .map_err(Self::db_error)?;
+        row.map(SendMode::from).ok_or_else(|| LibError::not_found(&request.id))
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), LibError> {
+        let client = get_connection(&self.pool).await?;
+        let stmt = retry!(client.prepare_cached("DELETE FROM send_modes WHERE id = $1"), self.max_retries)
+            .map_err(Self::db_error)?;
+        let deleted = retry!(client.execute(&stmt, &[&id]), self.max_retries).map_err(Self::db_error)?;
+        if deleted == 0 {
+            return Err(LibError::not_found(id))
+        }
+        Ok(())
+    }
+
+    pub async fn update_heartbeat(&self, id: &str, at: DateTime<Utc>) -> Result<(), LibError> {
+        let client = get_connection(&self.pool).await?;
+        let stmt = retry!(client.prepare_cached("UPDATE send_modes SET last_heartbeat = $2 WHERE id = $1"), self.max_retries)
+            .map_err(Self::db_error)?;
+        let updated = retry!(client.execute(&stmt, &[&id, &at]), self.max_retries).map_err(Self::db_error)?;
+        if updated == 0 {
+            return Err(LibError::not_found(id))
+        }
+        Ok(())
+    }
+
+    fn db_error(e: String) -> LibError {
+        error!(err=e, "Send mode repository error");
+        LibError::DatabaseError(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DurationRound;
+    use crate::repository::test_pool;
+    use crate::send_modes::fixtures;
+    use super::*;
+
+    fn send_mode(id: &str, name: &str) -> SendMode {
+        SendMode {
+            name: name.to_owned(),
+            fingerprint: Some("fingerprint".to_owned()),
+            auto_heartbeat_interval: Some(30),
+            // Postgres keeps microseconds
+            last_heartbeat: Utc::now().duration_trunc(chrono::Duration::seconds(1)).unwrap(),
+            ..fixtures::send_mode(id)
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn inserts_once_and_reads_back() {
+        let (pool, _) = test_pool(CREATE_SEND_MODES_TABLE).await;
+        let repository = SendModeRepository::new(pool);
+        let inserted = repository.insert(&send_mode("1", "phone")).await.unwrap();
+        assert_eq!((inserted.name.as_str(), inserted.private_key.is_none()), ("phone", true));
+        assert!(matches!(repository.insert(&send_mode("1", "other")).await, Err(LibError::DatabaseError(_))));
+
+        let stored = repository.get_by_id("1").await.unwrap();
+        assert_eq!(stored.name, "phone");
+        assert_eq!(stored.fingerprint.as_deref(), Some("fingerprint"));
+        assert_eq!(stored.auto_heartbeat_interval, Some(30));
+        assert_eq!(stored.last_heartbeat, inserted.last_heartbeat);
+        assert!(matches!(repository.get_by_id("2").await, Err(LibError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn lists_renames_and_deletes() {
+        let (pool, _) = test_pool(CREATE_SEND_MODES_TABLE).await;
+        let repository = SendModeRepository::new(pool);
+        repository.insert(&send_mode("1", "b")).await.unwrap();
+        repository.insert(&send_mode("2", "a")).await.unwrap();
+        let names = |modes: Vec<SendMode>| modes.into_iter().map(|mode| mode.name).collect::<Vec<_>>();
+        assert_eq!(names(repository.list_by_aggregate_id("aggregate").await.unwrap()), vec!["a", "b"]);
+
+        let renamed = repository.rename(&RenameSendModeRequest { id: "1".to_owned(), name: "c".to_owned() }).await.unwrap();
+        assert_eq!(renamed.name, "c");
+        let missing = RenameSendModeRequest { id: "3".to_owned(), name: "c".to_owned() };
+        assert!(matches!(repository.rename(&missing).await, Err(LibError::NotFound(_))));
+
+        let at = Utc::now().duration_trunc(chrono::Duration::seconds(1)).unwrap();
+        repository.update_heartbeat("2", at).await.unwrap();
+        assert_eq!(repository.get_by_id("2").await.unwrap().last_heartbeat, at);
+        assert!(matches!(repository.update_heartbeat("3", at).await, Err(LibError::NotFound(_))));
+
+        repository.delete("1").await.unwrap();
+        assert!(matches!(repository.delete("1").await, Err(LibError::NotFound(_))));
+        assert_eq!(names(repository.list_by_aggregate_id("aggregate").await.unwrap()), vec!["a"]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::time::Duration;
+use deadpool_postgres::tokio_postgres::tls::{MakeTlsConnect, TlsConnect};
+use deadpool_postgres::tokio_postgres::{AsyncMessage, Client, Config, NoTls, Notification, Socket};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+use crate::repository::template_repository::{TemplateRepository, TEMPLATES_CHANNEL};
+use crate::send_modes::error::LibError;
+use crate::send_modes::template_set::SharedTemplateSet;
+
+/// Reloads the whole set after every (re)connect, so changes made while disconnected are not lost.
+pub struct TemplateChangeFeed<T = NoTls> {
+    config: Config,
+    tls: T,
+    repository: Arc<TemplateRepository>,
+    templates: SharedTemplateSet,
+    reconnect_delay: Duration,
+}
+
+pub struct TemplateFeedHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl TemplateFeedHandle {
+    pub async fn shutdown(self) {
+        self.shutdown.send_replace(true);
+        if let Err(e) = self.task.await && !e.is_cancelled() {
+            error!(err=e.to_string(), "Template change feed task failed");
+        }
+    }
+}
+
+impl TemplateChangeFeed {
+    pub fn new(config: Config, repository: Arc<TemplateRepository>, templates: SharedTemplateSet) -> Self {
+        Self::with_tls(config, NoTls, repository, templates)
+    }
+}
+
+impl<T> TemplateChangeFeed<T>
+where
+    T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+    T::Stream: Send,
+    T::TlsConnect: Send,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    pub fn with_tls(config: Config, tls: T, repository: Arc<TemplateRepository>, templates: SharedTemplateSet) -> Self {
+        Self { config, tls, repository, templates, reconnect_delay: Duration::from_secs(5) }
+    }
+
+    pub fn with_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    pub async fn reload(&self) -> Result<(), LibError> {
+        let templates = self.repository.load_all().await?;
+        let count = templates.len();
+        let rejected = self.templates.reload(templates);
+        info!(templates=count, rejected=rejected, "Templates reloaded");
+        Ok(())
+    }
+
+    pub fn spawn(self) -> TemplateFeedHandle {
+        let (shutdown, receiver) = watch::channel(false);
+        let task = tokio::spawn(self.run(receiver));
+        TemplateFeedHandle { shutdown, task }
+    }
+
+    async fn run(self, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            match self.listen(&mut shutdown).await {
+                Ok(()) => return,
+                Err(e) => error!(err=e.to_string(), "Template change feed error, reconnecting"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.reconnect_delay) => {}
+                _ = shutdown.wait_for(|stop| *stop) => return,
+            }
+        }
+    }
+
+    async fn listen(&self, shutdown: &mut watch::Receiver<bool>) -> Result<(), LibError> {
+        let (client, mut connection) = self.config.connect(self.tls.clone()).await.map_err(|e| LibError::DatabaseError(e.to_string()))?;
+        let (sender, mut notifications) = mpsc::unbounded_channel();
+        let driver = tokio::spawn(async move {
+            while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+                match message {
+                    Ok(AsyncMessage::Notification(notification)) => {
+                        if sender.send(notification).is_err() {
+                            return
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!(err=e.to_string(), "Template listen connection error");
+                        return
+                    }
+                }
+            }
+        });
+        let result = self.receive(&client, &mut notifications, shutdown).await;
+        driver.abort();
+        result
+    }
+
+    async fn receive(
+        &self,
+        client: &Client,
+        notifications: &mut mpsc::UnboundedReceiver<Notification>,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> Result<(), LibError> {
+        client.batch_execute(&format!("LISTEN {TEMPLATES_CHANNEL}")).await
+            .map_err(|e| LibError::DatabaseError(e.to_string()))?;
+        self.reload().await?;
+        loop {
+            tokio::select! {
+                notification = notifications.recv() => {
+                    let Some(notification) = notification else {
+                        return Err(LibError::DatabaseError("listen connection closed".to_owned()))
+                    };
+                    // A transaction touching many templates notifies once per row, one reload is enough
+                    let mut changes = 1;
+                    while notifications.try_recv().is_ok() {
+                        changes += 1;
+                    }
+                    debug!(send_mode=notification.payload(), changes=changes, "Templates changed");
+                    self.reload().await?;
+                }
+                _ = stopped(shutdown) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Unlike `wait_for` does not hold the watch guard, so it can be a `select!` branch next to other awaits.
+async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::template_repository::CREATE_TEMPLATES_TABLE;
+    use crate::repository::template_repository::tests::template;
+    use crate::repository::test_pool;
+    use crate::send_modes::notification_types::NotificationTemplate;
+
+    async fn wait_for_len(templates: &SharedTemplateSet, len: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while templates.load().len() != len {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }).await.unwrap_or_else(|_| panic!("template set did not reach {len} templates"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn reloads_on_change_and_skips_broken_templates() {
+        let (pool, config) = test_pool(CREATE_TEMPLATES_TABLE).await;
+        let repository = Arc::new(TemplateRepository::new(pool));
+        repository.insert(&template("Зачисление {amount}р")).await.unwrap();
+        let templates = SharedTemplateSet::default();
+        let feed = TemplateChangeFeed::new(config, repository.clone(), templates.clone())
+            .with_reconnect_delay(Duration::from_millis(50))
+            .spawn();
+        wait_for_len(&templates, 1).await;
+
+        let broken = NotificationTemplate { notification_type: "carrier_pigeon".to_owned(), ..template("Письмо {amount}р") };
+        repository.insert(&broken).await.unwrap();
+        repository.insert(&template("Пополнение {amount}р")).await.unwrap();
+        wait_for_len(&templates, 2).await;
+        assert_eq!(templates.load().rejected().len(), 1);
+        feed.shutdown().await;
+    }
+}
@@ -0,0 +1,329 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use deadpool_postgres::tokio_postgres::Row;
+use deadpool_postgres::tokio_postgres::types::ToSql;
+use tracing::{error, info, warn};
+use crate::retry;
+use crate::repository::{get_connection, DEFAULT_MAX_RETRIES};
+use crate::send_modes::error::LibError;
+use crate::send_modes::notification_types::NotificationTemplate;
+use crate::send_modes::send_mode::SendModeEnum;
+use crate::tools::is_connection_err;
+
+pub const TEMPLATES_CHANNEL: &str = "notification_templates";
+
+pub const CREATE_TEMPLATES_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS notification_templates (
+    id BIGSERIAL PRIMARY KEY,
+    bank VARCHAR NOT NULL,
+    send_mode VARCHAR NOT NULL,
+    notification_type VARCHAR NOT NULL,
+    source VARCHAR NOT NULL,
+    template TEXT NOT NULL,
+    search_by VARCHAR NOT NULL,
+    has_requisite BOOLEAN NOT NULL DEFAULT false,
+    has_balance BOOLEAN NOT NULL DEFAULT false,
+    need_to_replace_comma BOOLEAN NOT NULL DEFAULT false,
+    currency VARCHAR,
+    priority INTEGER NOT NULL DEFAULT 0,
+    exclude TEXT[] NOT NULL DEFAULT '{}',
+    kind VARCHAR NOT NULL,
+    version INTEGER NOT NULL DEFAULT 1,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS notification_templates_lookup_idx
+    ON notification_templates (bank, send_mode, notification_type);
+CREATE TABLE IF NOT EXISTS notification_template_revisions (
+    template_id BIGINT NOT NULL REFERENCES notification_templates (id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    bank VARCHAR NOT NULL,
+    send_mode VARCHAR NOT NULL,
+    notification_type VARCHAR NOT NULL,
+    source VARCHAR NOT NULL,
+    template TEXT NOT NULL,
+    search_by VARCHAR NOT NULL,
+    has_requisite BOOLEAN NOT NULL,
+    has_balance BOOLEAN NOT NULL,
+    need_to_replace_comma BOOLEAN NOT NULL,
+    currency VARCHAR,
+    priority INTEGER NOT NULL,
+    exclude TEXT[] NOT NULL,
+    kind VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (template_id, version)
+);
+CREATE OR REPLACE FUNCTION notify_notification_templates() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('notification_templates', COALESCE(NEW.send_mode, OLD.send_mode));
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS notification_templates_notify ON notification_templates;
+CREATE TRIGGER notification_templates_notify AFTER INSERT OR UPDATE OR DELETE ON notification_templates
+    FOR EACH ROW EXECUTE FUNCTION notify_notification_templates();";
+
+/// Adds `kind` to older tables. Backfill every row, then set the column `NOT NULL`,
+/// until then rows without a kind are left out of template sets.
+pub const ADD_TEMPLATE_KIND: &str = "
+ALTER TABLE notification_templates ADD COLUMN IF NOT EXISTS kind VARCHAR;
+ALTER TABLE notification_template_revisions ADD COLUMN IF NOT EXISTS kind VARCHAR;";
+
+const TEMPLATE_COLUMNS: &str = "bank, send_mode, notification_type, source, template, search_by, \
+    has_requisite, has_balance, need_to_replace_comma, currency, priority, exclude, kind";
+
+#[derive(Debug, Clone)]
+pub struct StoredTemplate {
+    pub id: i64,
+    pub version: i32,
+    pub updated_at: DateTime<Utc>,
+    pub template: NotificationTemplate,
+}
+
+impl From<&Row> for StoredTemplate {
+    fn from(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            version: row.get("version"),
+            updated_at: row.get("updated_at"),
+            template: NotificationTemplate::from(row),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TemplateRevision {
+    pub template_id: i64,
+    pub version: i32,
+    pub created_at: DateTime<Utc>,
+    pub template: NotificationTemplate,
+}
+
+impl From<&Row> for TemplateRevision {
+    fn from(row: &Row) -> Self {
+        Self {
+            template_id: row.get("template_id"),
+            version: row.get("version"),
+            created_at: row.get("created_at"),
+            template: NotificationTemplate::from(row),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TemplateFilter {
+    pub bank: Option<String>,
+    pub send_mode: Option<SendModeEnum>,
+    pub notification_type: Option<String>,
+}
+
+pub struct TemplateRepository {
+    pool: Pool,
+    max_retries: usize,
+}
+
+impl TemplateRepository {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool, max_retries: DEFAULT_MAX_RETRIES }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub async fn get(&self, id: i64) -> Result<StoredTemplate, LibError> {
+        let client = get_connection(&self.pool).await?;
+        let sql = format!("SELECT id, version, updated_at, {TEMPLATE_COLUMNS} FROM notification_templates WHERE id = $1");
+        let stmt = retry!(client.prepare_cached(&sql), self.max_retries).map_err(Self::db_error)?;
+        let row = retry!(client.query_opt(&stmt, &[&id]), self.max_retries).map_err(Self::db_error)?;
+        row.as_ref().map(StoredTemplate::from).ok_or_else(|| LibError::not_found(id.to_string()))
+    }
+
+    pub async fn list(&self, filter: &TemplateFilter) -> Result<Vec<StoredTemplate>, LibError> {
+        let client = get_connection(&self.pool).await?;
+        let sql = format!("SELECT id, version, updated_at, {TEMPLATE_COLUMNS} FROM notification_templates \
+            WHERE ($1::VARCHAR IS NULL OR bank = $1) \
+            AND ($2::VARCHAR IS NULL OR send_mode = $2) \
+            AND ($3::VARCHAR IS NULL OR notification_type = $3) \
+            ORDER BY id");
+        let stmt = retry!(client.prepare_cached(&sql), self.max_retries).map_err(Self::db_error)?;
+        let rows = retry!(client.query(&stmt, &[&filter.bank, &filter.send_mode, &filter.notification_type]), self.max_retries)
+            .map_err(Self::db_error)?;
+        Ok(rows.iter().map(StoredTemplate::from).collect())
+    }
+
+    pub async fn load_all(&self) -> Result<Vec<NotificationTemplate>, LibError> {
+        Ok(self.list(&TemplateFilter::default()).await?.into_iter().map(|stored| stored.template).collect())
+    }
+
+    pub async fn insert(&self, template: &NotificationTemplate) -> Result<StoredTemplate, LibError> {
+        let mut client = get_connection(&self.pool).await?;
+        let tx = client.transaction().await.map_err(|e| Self::db_error(e.to_string()))?;
+        let sql = format!("INSERT INTO notification_templates ({TEMPLATE_COLUMNS}) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) \
+            RETURNING id, version, updated_at, {TEMPLATE_COLUMNS}");
+        let row = tx.query_one(&sql, &Self::params(template)).await.map_err(|e| Self::db_error(e.to_string()))?;
+        let stored = StoredTemplate::from(&row);
+        Self::save_revision(&tx, &stored).await?;
+        tx.commit().await.map_err(|e| Self::db_error(e.to_string()))?;
+        info!(id=stored.id, bank=stored.template.bank, "Template created");
+        Ok(stored)
+    }
+
+    pub async fn update(&self, id: i64, template: &NotificationTemplate) -> Result<StoredTemplate, LibError> {
+        let mut client = get_connection(&self.pool).await?;
+        let tx = client.transaction().await.map_err(|e| Self::db_error(e.to_string()))?;
+        let stored = Self::update_in(&tx, id, template).await?;
+        tx.commit().await.map_err(|e| Self::db_error(e.to_string()))?;
+        info!(id=id, version=stored.version, "Template updated");
+        Ok(stored)
+    }
+
+    pub async fn delete(&self, id: i64) -> Result<(), LibError> {
+        let client = get_connection(&self.pool).await?;
+        let stmt = retry!(client.prepare_cached("DELETE FROM notification_templates WHERE id = $1"), self.max_retries)
+            .map_err(Self::db_error)?;
+        let deleted = retry!(client.execute(&stmt, &[&id]), self.max_retries).map_err(Self::db_error)?;
+        if deleted == 0 {
+            return Err(LibError::not_found(id.to_string()))
+        }
+        Ok(())
+    }
+
+    pub async fn revisions(&self, id: i64) -> Result<Vec<TemplateRevision>, LibError> {
+        let client = get_connection(&self.pool).await?;
+        let sql = format!("SELECT template_id, version, created_at, {TEMPLATE_COLUMNS} \
+            FROM notification_template_revisions WHERE template_id = $1 ORDER BY version DESC");
+        let stmt = retry!(client.prepare_cached(&sql), self.max_retries).map_err(Self::db_error)?;
+        let rows = retry!(client.query(&stmt, &[&id]), self.max_retries).map_err(Self::db_error)?;
+        Ok(rows.iter().map(TemplateRevision::from).collect())
+    }
+
+    /// The restored content becomes a new version, so the rollback itself can be rolled back.
+    pub async fn rollback(&self, id: i64, version: i32) -> Result<StoredTemplate, LibError> {
+        let mut client = get_connection(&self.pool).await?;
+        let tx = client.transaction().await.map_err(|e| Self::db_error(e.to_string()))?;
+        let sql = format!("SELECT template_id, version, created_at, {TEMPLATE_COLUMNS} \
+            FROM notification_template_revisions WHERE template_id = $1 AND version = $2");
+        let revision = tx.query_opt(&sql, &[&id, &version]).await
+            .map_err(|e| Self::db_error(e.to_string()))?
+            .as_ref()
+            .map(TemplateRevision::from)
+            .ok_or_else(|| LibError::not_found(format!("{id}@{version}")))?;
+        let stored = Self::update_in(&tx, id, &revision.template).await?;
+        tx.commit().await.map_err(|e| Self::db_error(e.to_string()))?;
+        warn!(id=id, from=version, version=stored.version, "Template rolled back");
+        Ok(stored)
+    }
+
+    async fn update_in(client: &impl GenericClient, id: i64, template: &NotificationTemplate) -> Result<StoredTemplate, LibError> {
+        let sql = format!("UPDATE notification_templates SET \
+            bank = $1, send_mode = $2, notification_type = $3, source = $4, template = $5, search_by = $6, \
+            has_requisite = $7, has_balance = $8, need_to_replace_comma = $9, currency = $10, \
+            priority = $11, exclude = $12, kind = $13, version = version + 1, updated_at = now() \
+            WHERE id = $14 RETURNING id, version, updated_at, {TEMPLATE_COLUMNS}");
+        let mut params = Self::params(template).to_vec();
+        params.push(&id);
+        let row = client.query_opt(&sql, &params).await.map_err(|e| Self::db_error(e.to_string()))?;
+        let stored = row.as_ref().map(StoredTemplate::from).ok_or_else(|| LibError::not_found(id.to_string()))?;
+        Self::save_revision(client, &stored).await?;
+        Ok(stored)
+    }
+
+    async fn save_revision(client: &impl GenericClient, stored: &StoredTemplate) -> Result<(), LibError> {
+        let sql = format!("INSERT INTO notification_template_revisions (template_id, version, {TEMPLATE_COLUMNS}) \
+            VALUES ($14, $15, $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)");
+        let mut params = Self::params(&stored.template).to_vec();
+        params.push(&stored.id);
+        params.push(&stored.version);
+        client.execute(&sql, &params).await.map_err(|e| Self::db_error(e.to_string()))?;
+        Ok(())
+    }
+
+    fn params(template: &NotificationTemplate) -> [&(dyn ToSql + Sync); 13] {
+        [
+            &template.bank,
+            &template.send_mode,
+            &template.notification_type,
+            &template.source,
+            &template.template,
+            &template.search_by,
+            &template.has_requisite,
+            &template.has_balance,
+            &template.need_to_replace_comma,
+            &template.currency,
+            &template.priority,
+            &template.exclude,
+            &template.kind,
+        ]
+    }
+
+    fn db_error(e: String) -> LibError {
+        error!(err=e, "Template repository error");
+        LibError::DatabaseError(e)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::repository::test_pool;
+    use crate::send_modes::fixtures;
+    use crate::send_modes::event::TransactionKind;
+
+    pub(crate) fn template(text: &str) -> NotificationTemplate {
+        NotificationTemplate { exclude: vec!["Отмена".to_owned()], ..fixtures::template(text) }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn keeps_revisions_and_rolls_back() {
+        let (pool, _) = test_pool(CREATE_TEMPLATES_TABLE).await;
+        let repository = TemplateRepository::new(pool);
+        let stored = repository.insert(&template("Зачисление {amount}р")).await.unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(stored.template.exclude, vec!["Отмена".to_owned()]);
+        assert_eq!(stored.template.kind, Some(TransactionKind::Credit));
+
+        let updated = repository.update(stored.id, &template("Пополнение {amount}р")).await.unwrap();
+        assert_eq!(updated.version, 2);
+        let revisions = repository.revisions(stored.id).await.unwrap();
+        assert_eq!(revisions.iter().map(|r| r.version).collect::<Vec<_>>(), vec![2, 1]);
+
+        let restored = repository.rollback(stored.id, 1).await.unwrap();
+        assert_eq!(restored.version, 3);
+        assert_eq!(restored.template.template, "Зачисление {amount}р");
+        assert!(matches!(repository.rollback(stored.id, 9).await, Err(LibError::NotFound(_))));
+
+        repository.delete(stored.id).await.unwrap();
+        assert!(matches!(repository.get(stored.id).await, Err(LibError::NotFound(_))));
+        assert!(repository.revisions(stored.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn rejects_template_without_kind() {
+        let (pool, _) = test_pool(CREATE_TEMPLATES_TABLE).await;
+        let repository = TemplateRepository::new(pool);
+        let template = NotificationTemplate { kind: None, ..template("Зачисление {amount}р") };
+        assert!(matches!(repository.insert(&template).await, Err(LibError::DatabaseError(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn migrates_rows_without_kind() {
+        let old_schema = format!("{CREATE_TEMPLATES_TABLE}
+            ALTER TABLE notification_templates DROP COLUMN kind;
+            ALTER TABLE notification_template_revisions DROP COLUMN kind;");
+        let (pool, _) = test_pool(&old_schema).await;
+        let client = get_connection(&pool).await.unwrap();
+        client.batch_execute("INSERT INTO notification_templates (bank, send_mode, notification_type, source, template, search_by) \
+            VALUES ('sber', 'KRAFT', 'sms', '900', 'Зачисление {amount}р', 'amount')").await.unwrap();
+        client.batch_execute(ADD_TEMPLATE_KIND).await.unwrap();
+        let repository = TemplateRepository::new(pool);
+        let stored = repository.list(&TemplateFilter::default()).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].template.kind, None);
+    }
+}
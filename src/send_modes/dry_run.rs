@@ -0,0 +1,280 @@
+use std::fmt::Display;
+use serde::{Deserialize, Serialize};
+use crate::send_modes::event::{Event, TextMessage};
+use crate::send_modes::money::MoneyRules;
+use crate::send_modes::notification_types::NotificationTemplate;
+use crate::send_modes::send_mode::SendModeEnum;
+use crate::send_modes::template_set::TemplateSet;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sample {
+    pub message: TextMessage,
+    #[serde(default)]
+    pub send_mode: Option<SendModeEnum>,
+    #[serde(default)]
+    pub expected: Option<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MatchedTemplate {
+    pub index: usize,
+    pub bank: String,
+    pub send_mode: SendModeEnum,
+    pub priority: i32,
+    pub template: String,
+}
+
+impl Display for MatchedTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {} {} priority {}: {}", self.index, self.bank, self.send_mode, self.priority, self.template)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SampleOutcome {
+    Passed,
+    Mismatch { diffs: Vec<FieldDiff> },
+    Missed,
+    Unexpected { event: Event },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SampleReport {
+    pub index: usize,
+    pub text: String,
+    pub outcome: SampleOutcome,
+    pub matched: Vec<MatchedTemplate>,
+}
+
+impl SampleReport {
+    pub fn is_ambiguous(&self) -> bool {
+        matches!(self.matched.as_slice(), [first, second, ..] if first.priority == second.priority)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DryRunReport {
+    pub samples: Vec<SampleReport>,
+    pub rejected: Vec<String>,
+}
+
+impl DryRunReport {
+    pub fn passed(&self) -> usize {
+        self.samples.iter().filter(|s| s.outcome == SampleOutcome::Passed).count()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &SampleReport> {
+        self.samples.iter().filter(|s| s.outcome != SampleOutcome::Passed)
+    }
+
+    pub fn ambiguous(&self) -> impl Iterator<Item = &SampleReport> {
+        self.samples.iter().filter(|s| s.is_ambiguous())
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.rejected.is_empty() && self.failed().next().is_none() && self.ambiguous().next().is_none()
+    }
+}
+
+impl Display for DryRunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for rejected in &self.rejected {
+            writeln!(f, "rejected {rejected}")?;
+        }
+        for sample in self.samples.iter().filter(|s| s.outcome != SampleOutcome::Passed || s.is_ambiguous()) {
+            writeln!(f, "#{} {:?}", sample.index, sample.text)?;
+            match &sample.outcome {
+                SampleOutcome::Passed => {}
+                SampleOutcome::Mismatch { diffs } => for diff in diffs {
+                    writeln!(f, "  {}: expected {}, got {}", diff.field, diff.expected, diff.actual)?;
+                },
+                SampleOutcome::Missed => writeln!(f, "  no template matched")?,
+                SampleOutcome::Unexpected { event } => writeln!(f, "  unexpected match: {} {}", event.bank, event.amount)?,
+                SampleOutcome::Failed { error } => writeln!(f, "  error: {error}")?,
+            }
+            if sample.is_ambiguous() {
+                writeln!(f, "  ambiguous, matched by:")?;
+                for template in &sample.matched {
+                    writeln!(f, "    {template}")?;
+                }
+            }
+        }
+        write!(f, "{} of {} samples passed, {} ambiguous, {} templates rejected",
+            self.passed(), self.samples.len(), self.ambiguous().count(), self.rejected.len())
+    }
+}
+
+pub struct DryRun {
+    set: TemplateSet,
+}
+
+impl DryRun {
+    pub fn new(templates: Vec<NotificationTemplate>) -> Self {
+        Self::with_rules(templates, &MoneyRules::default())
+    }
+
+    pub fn with_rules(templates: Vec<NotificationTemplate>, rules: &MoneyRules) -> Self {
+        Self { set: TemplateSet::with_rules(templates, rules) }
+    }
+
+    pub fn run(&self, samples: &[Sample]) -> DryRunReport {
+        DryRunReport {
+            samples: samples.iter().enumerate().map(|(index, sample)| self.check(index, sample)).collect(),
+            rejected: self.set.rejected().iter()
+                .map(|r| format!("{} {:?}: {}", r.template.bank, r.template.template, r.error))
+                .collect(),
+        }
+    }
+
+    fn check(&self, index: usize, sample: &Sample) -> SampleReport {
+        let mut matched = Vec::new();
+        let mut first = None;
+        for (index, template) in self.set.positioned_candidates(&sample.message, sample.send_mode.as_ref()) {
+            let result = match template.extract(&sample.message) {
+                Ok(Some(event)) => Ok(event),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            matched.push(MatchedTemplate {
+                index,
+                bank: template.template().bank.clone(),
+                send_mode: template.template().send_mode.clone(),
+                priority: template.template().priority,
+                template: template.template().template.clone(),
+            });
+            first.get_or_insert(result);
+        }
+        let outcome = match (first, &sample.expected) {
+            (None, None) => SampleOutcome::Passed,
+            (None, Some(_)) => SampleOutcome::Missed,
+            (Some(Err(e)), _) => SampleOutcome::Failed { error: e.to_string() },
+            (Some(Ok(event)), None) => SampleOutcome::Unexpected { event },
+            (Some(Ok(event)), Some(expected)) => {
+                let diffs = diff(expected, &event);
+                if diffs.is_empty() { SampleOutcome::Passed } else { SampleOutcome::Mismatch { diffs } }
+            }
+        };
+        SampleReport { index, text: sample.message.text.clone(), outcome, matched }
+    }
+}
+
+fn diff(expected: &Event, actual: &Event) -> Vec<FieldDiff> {
+    let mut diffs = Vec::new();
+    let mut compare = |field: &'static str, expected: String, actual: String| {
+        if expected != actual {
+            diffs.push(FieldDiff { field, expected, actual });
+        }
+    };
+    compare("mode_id", expected.mode_id.clone(), actual.mode_id.clone());
+    compare("bank", expected.bank.clone(), actual.bank.clone());
+    compare("amount", expected.amount.to_string(), actual.amount.to_string());
+    compare("requisite", format!("{:?}", expected.requisite), format!("{:?}", actual.requisite));
+    compare("balance", format!("{:?}", expected.balance.map(|b| b.to_string())), format!("{:?}", actual.balance.map(|b| b.to_string())));
+    compare("search_by", expected.search_by.clone(), actual.search_by.clone());
+    compare("kind", expected.kind.to_string(), actual.kind.to_string());
+    compare("counterparty", format!("{:?}", expected.counterparty), format!("{:?}", actual.counterparty));
+    compare("card", format!("{:?}", expected.card), format!("{:?}", actual.card));
+    compare("timestamp", format!("{:?}", expected.timestamp), format!("{:?}", actual.timestamp));
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use rust_decimal::Decimal;
+    use super::*;
+    use crate::send_modes::event::{EventType, TransactionKind};
+    use crate::send_modes::fixtures::template;
+    use crate::send_modes::money::{Currency, Money};
+
+
+    fn sample(text: &str, amount: Option<&str>) -> Sample {
+        Sample {
+            message: TextMessage {
+                mode_id: "mode".to_owned(),
+                source: "900".to_owned(),
+                text: text.to_owned(),
+                event_type: EventType::SMS,
+                subject: None,
+                sender: None,
+            },
+            send_mode: Some(SendModeEnum::KRAFT),
+            expected: amount.map(|amount| Event {
+                mode_id: "mode".to_owned(),
+                bank: "sber".to_owned(),
+                amount: Money::new(Decimal::from_str(amount).unwrap(), Currency::RUB),
+                requisite: None,
+                balance: None,
+                search_by: "amount".to_owned(),
+                kind: TransactionKind::Credit,
+                counterparty: None,
+                card: None,
+                timestamp: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn reports_outcomes_and_ambiguity() {
+        let dry_run = DryRun::new(vec![template("Зачисление {amount}р"), template("{*} {amount}р")]);
+        let report = dry_run.run(&[
+            sample("Зачисление 10р", Some("10.00")),
+            sample("Зачисление 10р", Some("11.00")),
+            sample("Списание 10р", None),
+            sample("Баланс", Some("1.00")),
+        ]);
+        let outcomes: Vec<_> = report.samples.iter().map(|s| &s.outcome).collect();
+        assert_eq!(outcomes[0], &SampleOutcome::Passed);
+        assert!(matches!(outcomes[1], SampleOutcome::Mismatch { diffs } if diffs[0].field == "amount"));
+        assert!(matches!(outcomes[2], SampleOutcome::Unexpected { .. }));
+        assert_eq!(outcomes[3], &SampleOutcome::Missed);
+        assert!(report.samples[0].is_ambiguous());
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn tries_templates_of_sample_send_mode() {
+        let other = NotificationTemplate { send_mode: SendModeEnum::TRADEMO, ..template("{*} {amount}р") };
+        let dry_run = DryRun::new(vec![other, template("Зачисление {amount}р")]);
+        let report = dry_run.run(&[sample("Зачисление 10р", Some("10.00"))]);
+        assert!(report.is_ok());
+        assert_eq!(report.samples[0].matched, vec![MatchedTemplate {
+            index: 1,
+            bank: "sber".to_owned(),
+            send_mode: SendModeEnum::KRAFT,
+            priority: 0,
+            template: "Зачисление {amount}р".to_owned(),
+        }]);
+
+        let any = Sample { send_mode: None, ..sample("Зачисление 10р", Some("10.00")) };
+        assert!(dry_run.run(&[any]).samples[0].is_ambiguous());
+    }
+
+    #[test]
+    fn priority_override_is_not_ambiguous() {
+        let specific = NotificationTemplate { priority: 1, ..template("Зачисление {amount}р") };
+        let dry_run = DryRun::new(vec![template("{*} {amount}р"), specific]);
+        let report = dry_run.run(&[sample("Зачисление 10р", Some("10.00"))]);
+        assert_eq!(report.samples[0].matched.len(), 2);
+        assert!(!report.samples[0].is_ambiguous());
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn reports_rejected_templates() {
+        let report = DryRun::new(vec![template("Зачисление {amount}р"), template("Без суммы")])
+            .run(&[sample("Зачисление 10р", Some("10.00"))]);
+        assert_eq!(report.samples[0].outcome, SampleOutcome::Passed);
+        assert_eq!(report.rejected.len(), 1);
+        assert!(!report.is_ok());
+    }
+}
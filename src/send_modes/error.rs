@@ -1,5 +1,26 @@
+use std::fmt::Display;
 use thiserror::Error;
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseDetails {
+    pub status: u16,
+    pub request_id: Option<String>,
+    pub body: String,
+}
+
+impl Display for ResponseDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "status {}", self.status)?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, ", request_id {request_id}")?;
+        }
+        if !self.body.is_empty() {
+            write!(f, ": {}", self.body)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum LibError {
     #[error("InternalError")]
@@ -8,15 +29,104 @@ pub enum LibError {
     TimeOut,
     #[error("IOError")]
     IOError(#[from] reqwest::Error),
-    #[error("{0}, Not found")]
-    NotFound(String),
-    #[error("Unauthorized")]
-    Unauthorized,
-    #[error("Forbidden")]
-    Forbidden,
+    #[error("Not found, {0}")]
+    NotFound(ResponseDetails),
+    #[error("Unauthorized, {0}")]
+    Unauthorized(ResponseDetails),
+    #[error("Forbidden, {0}")]
+    Forbidden(ResponseDetails),
+    #[error("Conflict, {0}")]
+    Conflict(ResponseDetails),
+    #[error("Unprocessable entity, {0}")]
+    UnprocessableEntity(ResponseDetails),
+    #[error("Too many requests, {0}")]
+    TooManyRequests(ResponseDetails),
+    #[error("Unexpected response, {0}")]
+    UnexpectedStatus(ResponseDetails),
     #[error("Invalid device mode")]
-    InvalidDeviceMode
+    InvalidDeviceMode,
+    #[error("Database error: {0}")]
+    DatabaseError(String),
+    #[error("Redis error: {0}")]
+    RedisError(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("Invalid credentials: {0}")]
+    InvalidCredentials(String),
 }
 
+impl LibError {
+    pub fn not_found(what: impl Into<String>) -> Self {
+        LibError::NotFound(ResponseDetails { status: 404, request_id: None, body: what.into() })
+    }
 
+    pub fn from_response(details: ResponseDetails) -> Self {
+        match details.status {
+            401 => LibError::Unauthorized(details),
+            403 => LibError::Forbidden(details),
+            404 => LibError::NotFound(details),
+            409 => LibError::Conflict(details),
+            422 => LibError::UnprocessableEntity(details),
+            429 => LibError::TooManyRequests(details),
+            _ => LibError::UnexpectedStatus(details),
+        }
+    }
 
+    /// Client errors are caused by the request itself and will fail the same way again.
+    pub fn is_client_error(&self) -> bool {
+        matches!(self,
+            LibError::NotFound(_)
+            | LibError::Unauthorized(_)
+            | LibError::Forbidden(_)
+            | LibError::Conflict(_)
+            | LibError::UnprocessableEntity(_)
+            | LibError::InvalidDeviceMode
+            | LibError::InvalidSignature(_)
+            | LibError::InvalidCredentials(_))
+            || matches!(self, LibError::UnexpectedStatus(details) if (400..500).contains(&details.status))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("Invalid template for bank {bank}: {reason}")]
+    InvalidTemplate { bank: String, reason: String },
+    #[error("Invalid {field} value: {value}")]
+    InvalidValue { field: &'static str, value: String },
+    #[error("No template matched message from {source_name} for mode {mode_id}")]
+    NoTemplateMatched { mode_id: String, source_name: String, tried: usize },
+    #[error("Unknown event type {0}")]
+    UnknownEventType(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(status: u16) -> ResponseDetails {
+        ResponseDetails { status, request_id: Some("request".to_owned()), body: "body".to_owned() }
+    }
+
+    #[test]
+    fn from_response_maps_status_to_variant() {
+        assert!(matches!(LibError::from_response(details(401)), LibError::Unauthorized(d) if d == details(401)));
+        assert!(matches!(LibError::from_response(details(403)), LibError::Forbidden(d) if d == details(403)));
+        assert!(matches!(LibError::from_response(details(404)), LibError::NotFound(d) if d == details(404)));
+        assert!(matches!(LibError::from_response(details(409)), LibError::Conflict(d) if d == details(409)));
+        assert!(matches!(LibError::from_response(details(422)), LibError::UnprocessableEntity(d) if d == details(422)));
+        assert!(matches!(LibError::from_response(details(429)), LibError::TooManyRequests(d) if d == details(429)));
+        assert!(matches!(LibError::from_response(details(400)), LibError::UnexpectedStatus(d) if d == details(400)));
+        assert!(matches!(LibError::from_response(details(503)), LibError::UnexpectedStatus(d) if d == details(503)));
+    }
+
+    #[test]
+    fn client_errors() {
+        assert!(LibError::from_response(details(400)).is_client_error());
+        assert!(LibError::from_response(details(404)).is_client_error());
+        assert!(!LibError::from_response(details(429)).is_client_error());
+        assert!(!LibError::from_response(details(502)).is_client_error());
+        assert!(LibError::not_found("mode").is_client_error());
+    }
+}
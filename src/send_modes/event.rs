@@ -1,27 +1,50 @@
+use std::error::Error;
 use std::fmt::Display;
 use std::str::FromStr;
-use rust_decimal::Decimal;
+use bytes::BufMut;
+use chrono::NaiveDateTime;
+use deadpool_postgres::tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use deadpool_postgres::tokio_postgres::types::private::BytesMut;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::warn;
+use crate::send_modes::error::ParseError;
+use crate::send_modes::money::Money;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum EventType {
     SMS,
-    PUSH
+    PUSH,
+    EMAIL,
+    WEBHOOK,
+    USSD,
+    TELEGRAM,
+    Unknown(String),
 }
 
+impl EventType {
+    pub fn is_known(&self) -> bool {
+        !matches!(self, EventType::Unknown(_))
+    }
+}
+
+/// Never fails, unknown names become [`EventType::Unknown`].
 impl From<&str> for EventType {
     fn from(s: &str) -> Self {
-        EventType::from_str(s).unwrap()
+        EventType::from_str(s).unwrap_or_else(|_| EventType::Unknown(s.to_owned()))
     }
 }
 
 impl FromStr for EventType {
-    type Err = ();
+    type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "sms" => Ok(EventType::SMS),
             "push_notification" => Ok(EventType::PUSH),
-            _ => Err(()),
+            "email" => Ok(EventType::EMAIL),
+            "bank_webhook" => Ok(EventType::WEBHOOK),
+            "ussd" => Ok(EventType::USSD),
+            "telegram_bot" => Ok(EventType::TELEGRAM),
+            _ => Err(ParseError::UnknownEventType(s.to_owned())),
         }
     }
 }
@@ -31,6 +54,11 @@ impl Display for EventType {
         match self {
             EventType::SMS => write!(f, "sms"),
             EventType::PUSH => write!(f, "push_notification"),
+            EventType::EMAIL => write!(f, "email"),
+            EventType::WEBHOOK => write!(f, "bank_webhook"),
+            EventType::USSD => write!(f, "ussd"),
+            EventType::TELEGRAM => write!(f, "telegram_bot"),
+            EventType::Unknown(s) => write!(f, "{s}"),
         }
     }
 }
@@ -49,10 +77,9 @@ impl From<&EventType> for String {
 
 impl From<String> for EventType {
     fn from(s: String) -> Self {
-        match s.as_str() {
-            "sms" => EventType::SMS,
-            "push_notification" => EventType::PUSH,
-            _ => unreachable!()
+        match EventType::from_str(&s) {
+            Ok(event_type) => event_type,
+            Err(_) => EventType::Unknown(s),
         }
     }
 }
@@ -73,40 +100,170 @@ impl<'de> Deserialize<'de> for EventType {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(EventType::from(s))
+        let event_type = EventType::from(s);
+        if let EventType::Unknown(name) = &event_type {
+            warn!(event_type=name, "Unknown event type");
+        }
+        Ok(event_type)
+    }
+}
+
+/// There is deliberately no default, a missing kind must not confirm a payment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    Credit,
+    Debit,
+    Refund,
+    Failed,
+    BalanceOnly,
+}
+
+impl TransactionKind {
+    pub fn confirms_payment(&self) -> bool {
+        matches!(self, TransactionKind::Credit)
+    }
+}
+
+impl FromStr for TransactionKind {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "credit" => Ok(TransactionKind::Credit),
+            "debit" => Ok(TransactionKind::Debit),
+            "refund" => Ok(TransactionKind::Refund),
+            "failed" => Ok(TransactionKind::Failed),
+            "balance_only" => Ok(TransactionKind::BalanceOnly),
+            _ => Err(ParseError::InvalidValue { field: "kind", value: s.to_owned() }),
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
+impl Display for TransactionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionKind::Credit => write!(f, "credit"),
+            TransactionKind::Debit => write!(f, "debit"),
+            TransactionKind::Refund => write!(f, "refund"),
+            TransactionKind::Failed => write!(f, "failed"),
+            TransactionKind::BalanceOnly => write!(f, "balance_only"),
+        }
+    }
+}
+
+impl ToSql for TransactionKind {
+    fn to_sql(&self, _ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put(self.to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::VARCHAR | Type::TEXT)
+    }
+
+    to_sql_checked!();
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Event {
     pub mode_id: String,
     pub bank: String,
-    pub amount: Decimal,
+    pub amount: Money,
     pub requisite: Option<String>,
-    pub balance: Option<Decimal>,
+    pub balance: Option<Money>,
     pub search_by: String,
+    pub kind: TransactionKind,
+    #[serde(default)]
+    pub counterparty: Option<String>,
+    #[serde(default)]
+    pub card: Option<String>,
+    /// Time of the transaction from the text, in the bank's local time.
+    #[serde(default)]
+    pub timestamp: Option<NaiveDateTime>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SendEvent {
     pub source: String,
     pub text: String,
     pub event_type: EventType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TextMessage {
     pub mode_id: String,
     pub source: String,
     pub text: String,
     pub event_type: EventType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+}
+
+impl TextMessage {
+    pub fn new(mode_id: impl Into<String>, event: SendEvent) -> Self {
+        Self {
+            mode_id: mode_id.into(),
+            source: event.source,
+            text: event.text,
+            event_type: event.event_type,
+            subject: event.subject,
+            sender: event.sender,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct Context {
-    pub amount: String,
+    pub kind: TransactionKind,
+    pub amount: Money,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub balance: Option<String>,
+    pub balance: Option<Money>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub requisite: Option<String>,
-}
\ No newline at end of file
+}
+
+impl From<&Event> for Context {
+    fn from(event: &Event) -> Self {
+        Self {
+            kind: event.kind,
+            amount: event.amount,
+            balance: event.balance,
+            requisite: event.requisite.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_event_type_does_not_panic() {
+        let message: TextMessage = serde_json::from_str(
+            r#"{"mode_id": "1", "source": "bank", "text": "text", "event_type": "carrier_pigeon"}"#
+        ).unwrap();
+        assert_eq!(message.event_type, EventType::Unknown("carrier_pigeon".to_owned()));
+        assert_eq!(message.event_type.to_string(), "carrier_pigeon");
+        assert!(EventType::from_str("carrier_pigeon").is_err());
+        assert!(serde_json::from_str::<EventType>("42").is_err());
+    }
+
+    #[test]
+    fn channel_event_types_round_trip() {
+        for event_type in [EventType::EMAIL, EventType::WEBHOOK, EventType::USSD, EventType::TELEGRAM] {
+            assert_eq!(EventType::from_str(&event_type.to_string()).unwrap(), event_type);
+        }
+        let event: SendEvent = serde_json::from_str(
+            r#"{"source": "bank", "text": "text", "event_type": "email", "subject": "Payment", "sender": "info@bank.ru"}"#
+        ).unwrap();
+        let message = TextMessage::new("1", event);
+        assert_eq!(message.event_type, EventType::EMAIL);
+        assert_eq!(message.subject.as_deref(), Some("Payment"));
+    }
+}
@@ -0,0 +1,38 @@
+//! Test values of the model types, vary them with struct update syntax.
+
+use chrono::Utc;
+use crate::send_modes::event::TransactionKind;
+use crate::send_modes::notification_types::NotificationTemplate;
+use crate::send_modes::send_mode::{SendMode, SendModeEnum};
+
+pub(crate) fn template(text: &str) -> NotificationTemplate {
+    NotificationTemplate {
+        bank: "sber".to_owned(),
+        send_mode: SendModeEnum::KRAFT,
+        template: text.to_owned(),
+        search_by: "amount".to_owned(),
+        has_requisite: false,
+        has_balance: false,
+        notification_type: "sms".to_owned(),
+        source: "900".to_owned(),
+        need_to_replace_comma: true,
+        currency: None,
+        priority: 0,
+        exclude: Vec::new(),
+        kind: Some(TransactionKind::Credit),
+    }
+}
+
+pub(crate) fn send_mode(id: &str) -> SendMode {
+    SendMode {
+        id: id.to_owned(),
+        aggregate_id: "aggregate".to_owned(),
+        name: id.to_owned(),
+        send_mode: SendModeEnum::KRAFT,
+        access_token: "token".to_owned(),
+        fingerprint: None,
+        private_key: None,
+        auto_heartbeat_interval: None,
+        last_heartbeat: Utc::now(),
+    }
+}
@@ -1,6 +1,12 @@
-
 pub mod event;
+pub mod money;
 pub mod notification_types;
 pub mod send_mode;
-mod client;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod parser;
+pub mod template_set;
+pub mod provider;
+pub mod dry_run;
+pub mod overlap;
+#[cfg(test)]
+pub(crate) mod fixtures;
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::str::FromStr;
+use bytes::BufMut;
+use deadpool_postgres::tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use deadpool_postgres::tokio_postgres::types::private::BytesMut;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use crate::send_modes::error::ParseError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Currency {
+    RUB,
+    USD,
+    EUR,
+    KZT,
+    UZS,
+    BYN,
+    KGS,
+    TJS,
+    AZN,
+    TRY,
+    GBP,
+    CNY,
+    JPY,
+}
+
+impl Currency {
+    pub fn minor_units(&self) -> u32 {
+        match self {
+            Currency::JPY => 0,
+            _ => 2,
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        let symbol = symbol.trim().trim_end_matches('.');
+        if let Ok(currency) = Currency::from_str(&symbol.to_uppercase()) {
+            return Some(currency)
+        }
+        match symbol.to_lowercase().as_str() {
+            "₽" | "р" | "руб" | "рублей" | "рубля" | "рубль" => Some(Currency::RUB),
+            "$" | "us$" => Some(Currency::USD),
+            "€" => Some(Currency::EUR),
+            "₸" | "тг" | "тенге" => Some(Currency::KZT),
+            "сум" | "so'm" => Some(Currency::UZS),
+            "br" => Some(Currency::BYN),
+            "₼" => Some(Currency::AZN),
+            "₺" => Some(Currency::TRY),
+            "£" => Some(Currency::GBP),
+            "¥" | "юань" => Some(Currency::CNY),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "RUB" => Ok(Currency::RUB),
+            "USD" => Ok(Currency::USD),
+            "EUR" => Ok(Currency::EUR),
+            "KZT" => Ok(Currency::KZT),
+            "UZS" => Ok(Currency::UZS),
+            "BYN" => Ok(Currency::BYN),
+            "KGS" => Ok(Currency::KGS),
+            "TJS" => Ok(Currency::TJS),
+            "AZN" => Ok(Currency::AZN),
+            "TRY" => Ok(Currency::TRY),
+            "GBP" => Ok(Currency::GBP),
+            "CNY" => Ok(Currency::CNY),
+            "JPY" => Ok(Currency::JPY),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl ToSql for Currency {
+    fn to_sql(&self, _ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put(self.to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::VARCHAR | Type::TEXT)
+    }
+
+    to_sql_checked!();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Money {
+    pub amount: Decimal,
+    pub currency: Currency,
+}
+
+impl Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.amount, self.currency)
+    }
+}
+
+impl Money {
+    pub fn new(amount: Decimal, currency: Currency) -> Self {
+        Self { amount, currency }
+    }
+
+    /// Parses an amount the way banks write it: `1 234,50 ₽`, `$1,234.50`, `1.234,50 EUR`.
+    pub fn parse(raw: &str, default_currency: Currency, decimal_comma: bool) -> Result<Self, ParseError> {
+        let invalid = || ParseError::InvalidValue { field: "amount", value: raw.to_owned() };
+        let compact: String = raw.chars().filter(|c| !c.is_whitespace()).collect();
+        let start = compact.find(|c: char| c.is_ascii_digit() || c == '-').ok_or_else(invalid)?;
+        let end = compact.rfind(|c: char| c.is_ascii_digit()).ok_or_else(invalid)? + 1;
+        let (prefix, number, suffix) = (&compact[..start], &compact[start..end], &compact[end..]);
+
+        let mut currency = default_currency;
+        for symbol in [prefix, suffix] {
+            if symbol.is_empty() || symbol == "." || symbol == "," {
+                continue
+            }
+            currency = Currency::from_symbol(symbol)
+                .ok_or_else(|| ParseError::InvalidValue { field: "currency", value: symbol.to_owned() })?;
+        }
+
+        let normalized = normalize_number(number, decimal_comma).ok_or_else(invalid)?;
+        let amount = Decimal::from_str(&normalized).map_err(|_| invalid())?;
+        Ok(Self { amount, currency })
+    }
+
+    pub fn round(self, format: &AmountFormat) -> Self {
+        let scale = format.scale.unwrap_or_else(|| self.currency.minor_units());
+        let mut amount = self.amount.round_dp_with_strategy(scale, format.rounding);
+        amount.rescale(scale);
+        Self { amount, currency: self.currency }
+    }
+}
+
+fn normalize_number(number: &str, decimal_comma: bool) -> Option<String> {
+    let (sign, digits) = match number.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", number),
+    };
+    let preferred = if decimal_comma { ',' } else { '.' };
+    let last = digits.rfind([',', '.']).map(|i| (i, digits.as_bytes()[i] as char));
+    let (integer, fraction) = match last {
+        None => return Some(number.to_owned()),
+        Some((i, separator)) => {
+            let (integer, fraction) = (&digits[..i], &digits[i + 1..]);
+            let repeated = integer.contains(separator);
+            let grouping = !integer.contains([',', '.']) && separator != preferred && fraction.len() == 3;
+            if repeated || grouping {
+                (digits, None)
+            } else {
+                (integer, Some(fraction))
+            }
+        }
+    };
+    let mut separators = integer.chars().filter(|c| matches!(c, ',' | '.'));
+    let thousands = separators.next();
+    if separators.any(|separator| Some(separator) != thousands) {
+        return None
+    }
+    let mut groups = integer.split([',', '.']);
+    let first = groups.next()?;
+    let grouped = thousands.is_some();
+    if first.is_empty() || (grouped && first.len() > 3) || !groups.all(|group| group.len() == 3) {
+        return None
+    }
+    let integer = integer.replace([',', '.'], "");
+    match fraction {
+        Some(fraction) if fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) => None,
+        Some(fraction) => Some(format!("{sign}{integer}.{fraction}")),
+        None => Some(format!("{sign}{integer}")),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AmountFormat {
+    pub scale: Option<u32>,
+    pub rounding: RoundingStrategy,
+}
+
+impl Default for AmountFormat {
+    fn default() -> Self {
+        Self { scale: None, rounding: RoundingStrategy::MidpointNearestEven }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MoneyRules {
+    pub default: AmountFormat,
+    pub banks: HashMap<String, AmountFormat>,
+}
+
+impl MoneyRules {
+    pub fn with_bank(mut self, bank: impl Into<String>, format: AmountFormat) -> Self {
+        self.banks.insert(bank.into(), format);
+        self
+    }
+
+    pub fn format(&self, bank: &str) -> AmountFormat {
+        self.banks.get(bank).copied().unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bank_formats() {
+        let rub = |value: &str| Money::new(Decimal::from_str(value).unwrap(), Currency::RUB);
+        assert_eq!(Money::parse("1 234,50 ₽", Currency::USD, true).unwrap(), rub("1234.50"));
+        assert_eq!(Money::parse("1\u{a0}234,50руб.", Currency::USD, true).unwrap(), rub("1234.50"));
+        assert_eq!(Money::parse("1.234,50", Currency::RUB, true).unwrap(), rub("1234.50"));
+        assert_eq!(
+            Money::parse("$1,234.5", Currency::RUB, false).unwrap(),
+            Money::new(Decimal::from_str("1234.5").unwrap(), Currency::USD),
+        );
+        assert!(Money::parse("12 ฿", Currency::RUB, true).is_err());
+    }
+
+    #[test]
+    fn decimal_comma_keeps_dot_decimals() {
+        let expected = Money::new(Decimal::from_str("1500.50").unwrap(), Currency::RUB);
+        assert_eq!(Money::parse("1500.50", Currency::RUB, true).unwrap(), expected);
+        assert_eq!(Money::parse("1.500,50", Currency::RUB, true).unwrap(), expected);
+        assert_eq!(Money::parse("1.500.000", Currency::RUB, true).unwrap().amount, Decimal::from(1_500_000));
+    }
+
+    #[test]
+    fn checks_thousands_groups() {
+        let amount = |raw: &str, decimal_comma| Money::parse(raw, Currency::RUB, decimal_comma).map(|money| money.amount.to_string());
+        assert_eq!(amount("1 234,50", false).unwrap(), "1234.50");
+        assert_eq!(amount("1500,50", false).unwrap(), "1500.50");
+        assert_eq!(amount("1,5", false).unwrap(), "1.5");
+        assert_eq!(amount("1,234", false).unwrap(), "1234");
+        assert_eq!(amount("1,234,567.5", false).unwrap(), "1234567.5");
+        assert_eq!(amount("1,234.50", true).unwrap(), "1234.50");
+        assert_eq!(amount("1,234", true).unwrap(), "1.234");
+        assert_eq!(amount("-1.234,5", false).unwrap(), "-1234.5");
+        for raw in ["1,23,4", "1.234.5", "12345,678.5", "1.234,5.6", "1,,5"] {
+            assert!(matches!(Money::parse(raw, Currency::RUB, false), Err(ParseError::InvalidValue { .. })), "{raw}");
+        }
+    }
+
+    #[test]
+    fn rounds_to_bank_scale() {
+        let money = Money::new(Decimal::from_str("10.005").unwrap(), Currency::RUB);
+        assert_eq!(money.round(&AmountFormat::default()).amount.to_string(), "10.00");
+        let up = AmountFormat { scale: Some(2), rounding: RoundingStrategy::MidpointAwayFromZero };
+        assert_eq!(money.round(&up).amount.to_string(), "10.01");
+        let whole = Money::new(Decimal::from(7), Currency::RUB).round(&AmountFormat::default());
+        assert_eq!(whole.amount.to_string(), "7.00");
+    }
+}
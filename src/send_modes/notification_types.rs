@@ -1,8 +1,11 @@
 use deadpool_postgres::tokio_postgres;
 use deadpool_redis::redis;
 use deadpool_redis::redis::{ErrorKind, FromRedisValue, RedisError, RedisWrite, ToRedisArgs, Value};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use tracing::error;
+use std::str::FromStr;
+use crate::send_modes::event::{EventType, TransactionKind};
+use crate::send_modes::money::Currency;
 use crate::send_modes::send_mode::SendModeEnum;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -16,6 +19,39 @@ pub struct NotificationTemplate {
     pub notification_type: String,
     pub source: String,
     pub need_to_replace_comma: bool,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// `None` when it is missing or unknown, such a template does not compile.
+    #[serde(default, deserialize_with = "lenient_kind")]
+    pub kind: Option<TransactionKind>,
+}
+
+fn lenient_kind<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<TransactionKind>, D::Error> {
+    let Some(kind) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None)
+    };
+    Ok(TransactionKind::from_str(&kind)
+        .inspect_err(|_| error!(kind=kind, "Unknown template kind"))
+        .ok())
+}
+
+impl NotificationTemplate {
+    pub fn event_type(&self) -> EventType {
+        EventType::from(self.notification_type.as_str())
+    }
+}
+
+fn row_kind(row: &tokio_postgres::Row) -> Option<TransactionKind> {
+    let kind = row.try_get::<_, Option<String>>("kind")
+        .inspect_err(|e| error!(err=e.to_string(), "Template kind column error"))
+        .ok()??;
+    TransactionKind::from_str(&kind)
+        .inspect_err(|_| error!(kind=kind, "Unknown template kind"))
+        .ok()
 }
 
 impl From<&tokio_postgres::Row> for NotificationTemplate {
@@ -30,6 +66,11 @@ impl From<&tokio_postgres::Row> for NotificationTemplate {
             notification_type: row.get("notification_type"),
             source: row.get("source"),
             need_to_replace_comma: row.get("need_to_replace_comma"),
+            currency: row.try_get::<_, Option<String>>("currency").ok().flatten()
+                .and_then(|currency| Currency::from_str(&currency).ok()),
+            priority: row.try_get("priority").unwrap_or_default(),
+            exclude: row.try_get("exclude").unwrap_or_default(),
+            kind: row_kind(row),
         }
     }
 }
@@ -46,6 +87,11 @@ impl From<tokio_postgres::Row> for NotificationTemplate {
             notification_type: row.get("notification_type"),
             source: row.get("source"),
             need_to_replace_comma: row.get("need_to_replace_comma"),
+            currency: row.try_get::<_, Option<String>>("currency").ok().flatten()
+                .and_then(|currency| Currency::from_str(&currency).ok()),
+            priority: row.try_get("priority").unwrap_or_default(),
+            exclude: row.try_get("exclude").unwrap_or_default(),
+            kind: row_kind(&row),
         }
     }
 }
@@ -75,4 +121,21 @@ impl ToRedisArgs for NotificationTemplate {
             error!(err=e.to_string(),"Error serializing notification template");
         }).unwrap().as_slice());
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_kind_reads_as_none() {
+        let json = |kind: &str| format!(r#"{{"bank":"sber","send_mode":"KRAFT","template":"Зачисление {{amount}}р",
+            "search_by":"amount","has_requisite":false,"has_balance":false,"notification_type":"sms",
+            "source":"900","need_to_replace_comma":true{kind}}}"#);
+        let parse = |kind: &str| serde_json::from_str::<NotificationTemplate>(&json(kind)).unwrap().kind;
+        assert_eq!(parse(r#","kind":"debit""#), Some(TransactionKind::Debit));
+        assert_eq!(parse(r#","kind":"chargeback""#), None);
+        assert_eq!(parse(r#","kind":null"#), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -0,0 +1,100 @@
+use serde::Serialize;
+use crate::send_modes::parser::CompiledTemplate;
+use crate::send_modes::template_set::TemplateSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Priority,
+    Order,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Overlap {
+    pub bank: String,
+    pub source: String,
+    pub notification_type: String,
+    pub winner: String,
+    pub loser: String,
+    pub example: String,
+    pub resolution: Resolution,
+}
+
+impl Overlap {
+    pub fn is_ambiguous(&self) -> bool {
+        self.resolution == Resolution::Order
+    }
+}
+
+/// A lint, texts the template examples do not cover are not checked.
+pub fn find_overlaps(set: &TemplateSet) -> Vec<Overlap> {
+    let mut overlaps = Vec::new();
+    for group in set.groups() {
+        for (i, first) in group.iter().enumerate() {
+            for second in &group[i + 1..] {
+                if let Some(overlap) = overlap(first, second) {
+                    overlaps.push(overlap);
+                }
+            }
+        }
+    }
+    overlaps
+}
+
+fn overlap(first: &CompiledTemplate, second: &CompiledTemplate) -> Option<Overlap> {
+    let (a, b) = (first.template(), second.template());
+    if a.bank != b.bank || a.send_mode != b.send_mode {
+        return None
+    }
+    let example = [first.example(), second.example()].into_iter()
+        .find(|example| first.matches(example) && second.matches(example))?;
+    let resolution = if a.priority != b.priority { Resolution::Priority } else { Resolution::Order };
+    Some(Overlap {
+        bank: a.bank.clone(),
+        source: a.source.clone(),
+        notification_type: a.notification_type.clone(),
+        winner: a.template.clone(),
+        loser: b.template.clone(),
+        example: example.to_owned(),
+        resolution,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send_modes::event::{EventType, TextMessage};
+    use crate::send_modes::fixtures;
+    use crate::send_modes::notification_types::NotificationTemplate;
+    use crate::send_modes::template_set::Verdict;
+
+    fn template(text: &str, priority: i32, exclude: &[&str]) -> NotificationTemplate {
+        let exclude = exclude.iter().map(|word| word.to_string()).collect();
+        NotificationTemplate { priority, exclude, ..fixtures::template(text) }
+    }
+
+    #[test]
+    fn reports_overlaps_and_explains_order() {
+        let set = TemplateSet::new(vec![
+            template("Перевод {*} {amount}р", 0, &["ивана"]),
+            template("{*} {amount}р", 0, &[]),
+            template("Перевод от {*} {amount}р", 5, &[]),
+        ]);
+        let overlaps = find_overlaps(&set);
+        assert!(overlaps.iter().any(|o| o.winner == "Перевод от {*} {amount}р" && o.resolution == Resolution::Priority));
+        let ambiguous: Vec<_> = overlaps.iter().filter(|o| o.is_ambiguous()).collect();
+        assert_eq!(ambiguous.len(), 1);
+        assert_eq!((ambiguous[0].winner.as_str(), ambiguous[0].loser.as_str()), ("Перевод {*} {amount}р", "{*} {amount}р"));
+
+        let message = TextMessage {
+            mode_id: "mode".to_owned(),
+            source: "900".to_owned(),
+            text: "Перевод от Ивана 10р".to_owned(),
+            event_type: EventType::SMS,
+            subject: None,
+            sender: None,
+        };
+        let verdicts: Vec<_> = set.explain(&message, None).into_iter().map(|e| e.verdict).collect();
+        assert_eq!(verdicts, vec![Verdict::Matched, Verdict::Excluded("ивана".to_owned()), Verdict::Shadowed]);
+    }
+}
@@ -0,0 +1,352 @@
+use chrono::{Datelike, NaiveDateTime, Utc};
+use regex::{Captures, Regex};
+use rust_decimal::Decimal;
+use tracing::debug;
+use crate::send_modes::error::ParseError;
+use crate::send_modes::event::{Event, TextMessage, TransactionKind};
+use crate::send_modes::money::{AmountFormat, Currency, Money, MoneyRules};
+use crate::send_modes::notification_types::NotificationTemplate;
+
+pub const AMOUNT_PLACEHOLDER: &str = "{amount}";
+pub const BALANCE_PLACEHOLDER: &str = "{balance}";
+pub const REQUISITE_PLACEHOLDER: &str = "{requisite}";
+pub const CURRENCY_PLACEHOLDER: &str = "{currency}";
+pub const COUNTERPARTY_PLACEHOLDER: &str = "{counterparty}";
+pub const CARD_PLACEHOLDER: &str = "{card}";
+pub const DATETIME_PLACEHOLDER: &str = "{datetime}";
+pub const SKIP_PLACEHOLDER: &str = "{*}";
+
+const DATETIME_FORMATS: [&str; 2] = ["%d.%m.%Y %H:%M:%S", "%d.%m.%Y %H:%M"];
+
+pub const DEFAULT_CURRENCY: Currency = Currency::RUB;
+
+const NUMBER_PATTERN: &str = r"-?(?:\d{1,3}(?:[ \x{a0}\x{202f}\x{2009}]\d{3})+(?:[.,]\d+)?|\d[\d.,]*)";
+
+/// Placeholders: `{amount}`, `{balance}`, `{requisite}`, `{currency}`, `{counterparty}`, `{card}`,
+/// `{datetime}` and `{*}` for any text that should be skipped.
+#[derive(Debug, Clone)]
+pub struct CompiledTemplate {
+    template: NotificationTemplate,
+    regex: Regex,
+    format: AmountFormat,
+    kind: TransactionKind,
+    exclude: Vec<String>,
+    example: String,
+}
+
+impl CompiledTemplate {
+    pub fn compile(template: NotificationTemplate) -> Result<Self, ParseError> {
+        Self::compile_with_rules(template, &MoneyRules::default())
+    }
+
+    pub fn compile_with_rules(template: NotificationTemplate, rules: &MoneyRules) -> Result<Self, ParseError> {
+        let invalid = |reason: &str| ParseError::InvalidTemplate {
+            bank: template.bank.clone(),
+            reason: reason.to_owned(),
+        };
+        if !template.event_type().is_known() {
+            return Err(invalid("unknown notification_type"));
+        }
+        let Some(kind) = template.kind else {
+            return Err(invalid("missing or unknown kind"));
+        };
+        if kind == TransactionKind::BalanceOnly {
+            if !template.template.contains(BALANCE_PLACEHOLDER) || !template.has_balance {
+                return Err(invalid("balance_only template needs has_balance and {balance} placeholder"));
+            }
+        } else if !template.template.contains(AMOUNT_PLACEHOLDER) {
+            return Err(invalid("missing {amount} placeholder"));
+        }
+        if template.has_balance && !template.template.contains(BALANCE_PLACEHOLDER) {
+            return Err(invalid("has_balance is set but {balance} placeholder is missing"));
+        }
+        if template.has_requisite && !template.template.contains(REQUISITE_PLACEHOLDER) {
+            return Err(invalid("has_requisite is set but {requisite} placeholder is missing"));
+        }
+        if template.exclude.iter().any(|word| word.trim().is_empty()) {
+            return Err(invalid("empty exclude word"));
+        }
+
+        let mut pattern = String::from(r"(?s)^\s*");
+        let mut example = String::new();
+        let mut rest = template.template.trim();
+        while !rest.is_empty() {
+            if let Some(tail) = rest.strip_prefix(AMOUNT_PLACEHOLDER) {
+                pattern.push_str(&format!("(?P<amount>{NUMBER_PATTERN})"));
+                example.push_str("100");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix(BALANCE_PLACEHOLDER) {
+                pattern.push_str(&format!("(?P<balance>{NUMBER_PATTERN})"));
+                example.push_str("100");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix(REQUISITE_PLACEHOLDER) {
+                pattern.push_str(r"(?P<requisite>\S+)");
+                example.push_str("*1234");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix(CURRENCY_PLACEHOLDER) {
+                pattern.push_str(r"(?P<currency>[^\s\d]{1,8})");
+                example.push_str("RUB");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix(COUNTERPARTY_PLACEHOLDER) {
+                pattern.push_str(r"(?P<counterparty>.+?)");
+                example.push_str("Иван И.");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix(CARD_PLACEHOLDER) {
+                pattern.push_str(r"(?P<card>[\w*•.\-]*\d{4})");
+                example.push_str("*1234");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix(DATETIME_PLACEHOLDER) {
+                pattern.push_str(r"(?P<datetime>\d{2}\.\d{2}(?:\.\d{2,4})?\s+\d{2}:\d{2}(?::\d{2})?)");
+                example.push_str("01.02.2025 10:15");
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix(SKIP_PLACEHOLDER) {
+                pattern.push_str(".*?");
+                rest = tail;
+            } else {
+                let ch = rest.chars().next().unwrap_or_default();
+                if ch.is_whitespace() {
+                    pattern.push_str(r"\s+");
+                    example.push(' ');
+                    rest = rest.trim_start();
+                } else {
+                    pattern.push_str(&regex::escape(ch.encode_utf8(&mut [0; 4])));
+                    example.push(ch);
+                    rest = &rest[ch.len_utf8()..];
+                }
+            }
+        }
+        pattern.push_str(r"\s*$");
+
+        let regex = Regex::new(&pattern).map_err(|e| invalid(&e.to_string()))?;
+        let format = rules.format(&template.bank);
+        let exclude = template.exclude.iter().map(|word| word.to_lowercase()).collect();
+        Ok(Self { template, regex, format, kind, exclude, example })
+    }
+
+    pub fn template(&self) -> &NotificationTemplate {
+        &self.template
+    }
+
+    pub fn example(&self) -> &str {
+        &self.example
+    }
+
+    pub fn excluded_by(&self, text: &str) -> Option<&str> {
+        let text = text.to_lowercase();
+        self.exclude.iter()
+            .position(|word| text.contains(word.as_str()))
+            .map(|i| self.template.exclude[i].as_str())
+    }
+
+    pub fn regex_matches(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        self.regex_matches(text) && self.excluded_by(text).is_none()
+    }
+
+    pub fn accepts(&self, message: &TextMessage) -> bool {
+        self.template.source == message.source
+            && self.template.notification_type == message.event_type.to_string()
+    }
+
+    pub fn extract(&self, message: &TextMessage) -> Result<Option<Event>, ParseError> {
+        let Some(captures) = self.regex.captures(&message.text) else {
+            return Ok(None)
+        };
+        if let Some(word) = self.excluded_by(&message.text) {
+            debug!(bank=self.template.bank, word=word, "Template excluded by word");
+            return Ok(None)
+        }
+        let balance = if self.template.has_balance {
+            self.money(&captures, "balance")?
+        } else {
+            None
+        };
+        let amount = match (self.money(&captures, "amount")?, balance) {
+            (Some(amount), _) => amount,
+            (None, Some(balance)) if self.kind == TransactionKind::BalanceOnly => {
+                Money::new(Decimal::ZERO, balance.currency).round(&self.format)
+            }
+            _ => return Err(ParseError::InvalidValue { field: "amount", value: String::new() }),
+        };
+        let text = |field: &str| captures.name(field).map(|m| m.as_str().trim().to_owned()).filter(|s| !s.is_empty());
+        let timestamp = match captures.name("datetime") {
+            Some(raw) => Some(parse_datetime(raw.as_str())
+                .ok_or_else(|| ParseError::InvalidValue { field: "datetime", value: raw.as_str().to_owned() })?),
+            None => None,
+        };
+        let requisite = if self.template.has_requisite {
+            captures.name("requisite").map(|m| m.as_str().to_owned())
+        } else {
+            None
+        };
+        Ok(Some(Event {
+            mode_id: message.mode_id.clone(),
+            bank: self.template.bank.clone(),
+            amount,
+            requisite,
+            balance,
+            search_by: self.template.search_by.clone(),
+            kind: self.kind,
+            counterparty: text("counterparty"),
+            card: text("card"),
+            timestamp,
+        }))
+    }
+
+    fn money(&self, captures: &Captures, field: &'static str) -> Result<Option<Money>, ParseError> {
+        let Some(raw) = captures.name(field) else {
+            return Ok(None)
+        };
+        let raw = raw.as_str().trim_end_matches(['.', ',']);
+        let currency = match captures.name("currency") {
+            Some(symbol) => Currency::from_symbol(symbol.as_str())
+                .ok_or_else(|| ParseError::InvalidValue { field: "currency", value: symbol.as_str().to_owned() })?,
+            None => self.template.currency.unwrap_or(DEFAULT_CURRENCY),
+        };
+        let money = Money::parse(raw, currency, self.template.need_to_replace_comma)
+            .map_err(|_| ParseError::InvalidValue { field, value: raw.to_owned() })?;
+        Ok(Some(money.round(&self.format)))
+    }
+}
+
+fn parse_datetime(raw: &str) -> Option<NaiveDateTime> {
+    let mut parts = raw.split_whitespace();
+    let (date, time) = (parts.next()?, parts.next()?);
+    let date = match date.len() {
+        5 => format!("{date}.{}", Utc::now().year()),
+        8 => format!("{}.20{}", &date[..5], &date[6..]),
+        _ => date.to_owned(),
+    };
+    let raw = format!("{date} {time}");
+    DATETIME_FORMATS.iter().find_map(|format| NaiveDateTime::parse_from_str(&raw, format).ok())
+}
+
+pub fn parse_message(message: &TextMessage, templates: &[CompiledTemplate]) -> Result<Event, ParseError> {
+    let notification_type = message.event_type.to_string();
+    let mut candidates: Vec<&CompiledTemplate> = templates.iter()
+        .filter(|compiled| compiled.template().source == message.source
+            && compiled.template().notification_type == notification_type)
+        .collect();
+    candidates.sort_by_key(|compiled| std::cmp::Reverse(compiled.template().priority));
+    extract_first(message, candidates)
+}
+
+pub(crate) fn extract_first<'a>(message: &TextMessage, candidates: impl IntoIterator<Item = &'a CompiledTemplate>)
+    -> Result<Event, ParseError>
+{
+    let mut tried = 0;
+    let mut failed = None;
+    for compiled in candidates {
+        tried += 1;
+        match compiled.extract(message) {
+            Ok(Some(event)) => return Ok(event),
+            Ok(None) => {}
+            Err(e) => {
+                debug!(bank=compiled.template().bank, err=e.to_string(), "Template failed to extract");
+                failed.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = failed {
+        return Err(e)
+    }
+    debug!(mode_id=message.mode_id, source=message.source, tried=tried, "No template matched");
+    Err(ParseError::NoTemplateMatched {
+        mode_id: message.mode_id.clone(),
+        source_name: message.source.clone(),
+        tried,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use super::*;
+    use crate::send_modes::event::EventType;
+    use crate::send_modes::fixtures;
+
+    fn template(text: &str, has_requisite: bool, has_balance: bool, need_to_replace_comma: bool) -> NotificationTemplate {
+        NotificationTemplate { has_requisite, has_balance, need_to_replace_comma, ..fixtures::template(text) }
+    }
+
+    fn compile(templates: Vec<NotificationTemplate>) -> Vec<CompiledTemplate> {
+        templates.into_iter().map(|template| CompiledTemplate::compile(template).unwrap()).collect()
+    }
+
+    fn message(text: &str) -> TextMessage {
+        TextMessage {
+            mode_id: "mode".to_owned(),
+            source: "900".to_owned(),
+            text: text.to_owned(),
+            event_type: EventType::SMS,
+            subject: None,
+            sender: None,
+        }
+    }
+
+    #[test]
+    fn extracts_all_fields() {
+        let templates = compile(vec![
+            template("{requisite} {*} Зачисление {amount}р Баланс: {balance}р", true, true, true),
+        ]);
+        let event = parse_message(&message("MIR-1234 10:15 Зачисление 1500,50р Баланс: 20000р"), &templates).unwrap();
+        assert_eq!(event.amount, Money::new(Decimal::from_str("1500.50").unwrap(), Currency::RUB));
+        assert_eq!(event.balance.unwrap().amount.to_string(), "20000.00");
+        assert_eq!(event.requisite.as_deref(), Some("MIR-1234"));
+        assert_eq!(event.bank, "sber");
+    }
+
+    #[test]
+    fn extracts_grouped_amount_with_currency() {
+        let templates = compile(vec![template("Покупка {amount} {currency} {*}", false, false, true)]);
+        let event = parse_message(&message("Покупка 1 234,50 $ в магазине"), &templates).unwrap();
+        assert_eq!(event.amount, Money::new(Decimal::from_str("1234.50").unwrap(), Currency::USD));
+    }
+
+    #[test]
+    fn falls_through_extraction_errors() {
+        let mut first = template("Покупка {amount} {currency}", false, false, true);
+        first.priority = 1;
+        let templates = compile(vec![first, template("Покупка {amount} {*}", false, false, true)]);
+        let event = parse_message(&message("Покупка 10 ฿"), &templates).unwrap();
+        assert_eq!(event.amount.amount.to_string(), "10.00");
+
+        let templates = compile(vec![template("Покупка {amount} {currency}", false, false, true)]);
+        let err = parse_message(&message("Покупка 10 ฿"), &templates).unwrap_err();
+        assert!(matches!(err, ParseError::InvalidValue { field: "currency", .. }));
+    }
+
+    #[test]
+    fn reports_no_match() {
+        let templates = compile(vec![template("Зачисление {amount}р", false, false, false)]);
+        let err = parse_message(&message("Списание 100р"), &templates).unwrap_err();
+        assert!(matches!(err, ParseError::NoTemplateMatched { tried: 1, .. }));
+    }
+
+    #[test]
+    fn extracts_kind_and_details() {
+        let mut debit = template("{datetime} Перевод {amount}р {counterparty} с карты {card}", false, false, true);
+        debit.kind = Some(TransactionKind::Debit);
+        let event = parse_message(&message("05.03.25 14:20 Перевод 500р Иван И. с карты MIR-1234"), &compile(vec![debit])).unwrap();
+        assert!(!event.kind.confirms_payment());
+        assert_eq!(event.counterparty.as_deref(), Some("Иван И."));
+        assert_eq!(event.card.as_deref(), Some("MIR-1234"));
+        assert_eq!(event.timestamp.unwrap().to_string(), "2025-03-05 14:20:00");
+
+        let mut balance = template("Баланс: {balance}р", false, true, true);
+        balance.kind = Some(TransactionKind::BalanceOnly);
+        let event = parse_message(&message("Баланс: 100р"), &compile(vec![balance])).unwrap();
+        assert_eq!(event.amount.amount, Decimal::ZERO);
+    }
+
+    #[test]
+    fn rejects_template_without_amount() {
+        assert!(CompiledTemplate::compile(template("Зачисление", false, false, false)).is_err());
+        let mut no_kind = template("Зачисление {amount}р", false, false, false);
+        no_kind.kind = None;
+        assert!(CompiledTemplate::compile(no_kind).is_err());
+    }
+}
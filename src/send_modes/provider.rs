@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::send_modes::event::EventType;
+use crate::send_modes::send_mode::{SendMode, SendModeEnum};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderCapabilities {
+    pub supports_sms: bool,
+    pub supports_push: bool,
+    pub extra_channels: Vec<EventType>,
+    pub needs_rsa_key: bool,
+    pub requires_heartbeat: bool,
+    pub default_heartbeat_interval: Option<Duration>,
+}
+
+impl ProviderCapabilities {
+    pub fn permissive() -> Self {
+        Self {
+            supports_sms: true,
+            supports_push: true,
+            extra_channels: Vec::new(),
+            needs_rsa_key: false,
+            requires_heartbeat: false,
+            default_heartbeat_interval: None,
+        }
+    }
+
+    pub fn supports(&self, event_type: &EventType) -> bool {
+        match event_type {
+            EventType::SMS => self.supports_sms,
+            EventType::PUSH => self.supports_push,
+            other => self.extra_channels.contains(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provider {
+    pub kind: SendModeEnum,
+    pub capabilities: ProviderCapabilities,
+    pub template_namespace: SendModeEnum,
+}
+
+impl Provider {
+    pub fn new(kind: SendModeEnum, capabilities: ProviderCapabilities) -> Self {
+        Self { template_namespace: kind.clone(), kind, capabilities }
+    }
+
+    pub fn with_template_namespace(mut self, namespace: SendModeEnum) -> Self {
+        self.template_namespace = namespace;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        self.kind.as_str()
+    }
+
+    pub fn check(&self, send_mode: &SendMode) -> Option<&'static str> {
+        if self.capabilities.needs_rsa_key && send_mode.private_key.is_none() {
+            return Some("private key is required")
+        }
+        if self.capabilities.requires_heartbeat
+            && send_mode.auto_heartbeat_interval.is_none_or(|interval| interval <= 0)
+            && self.capabilities.default_heartbeat_interval.is_none() {
+            return Some("heartbeat interval is required")
+        }
+        None
+    }
+}
+
+/// Unregistered kinds, including [`SendModeEnum::Unknown`], resolve to a permissive fallback.
+#[derive(Debug, Clone)]
+pub struct ProviderRegistry {
+    providers: HashMap<SendModeEnum, Provider>,
+    fallback: ProviderCapabilities,
+}
+
+impl Default for ProviderRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .with(Provider::new(SendModeEnum::KRAFT, ProviderCapabilities::permissive()))
+            .with(Provider::new(SendModeEnum::TRADEMO, ProviderCapabilities::permissive()))
+    }
+}
+
+impl ProviderRegistry {
+    pub fn empty() -> Self {
+        Self { providers: HashMap::new(), fallback: ProviderCapabilities::permissive() }
+    }
+
+    pub fn with(mut self, provider: Provider) -> Self {
+        self.register(provider);
+        self
+    }
+
+    pub fn with_fallback(mut self, capabilities: ProviderCapabilities) -> Self {
+        self.fallback = capabilities;
+        self
+    }
+
+    pub fn register(&mut self, provider: Provider) -> Option<Provider> {
+        self.providers.insert(provider.kind.clone(), provider)
+    }
+
+    pub fn is_registered(&self, kind: &SendModeEnum) -> bool {
+        self.providers.contains_key(kind)
+    }
+
+    pub fn get(&self, kind: &SendModeEnum) -> Provider {
+        self.providers.get(kind).cloned()
+            .unwrap_or_else(|| Provider::new(kind.clone(), self.fallback.clone()))
+    }
+
+    pub fn capabilities(&self, kind: &SendModeEnum) -> &ProviderCapabilities {
+        self.providers.get(kind).map_or(&self.fallback, |provider| &provider.capabilities)
+    }
+
+    pub fn template_namespace<'a>(&'a self, kind: &'a SendModeEnum) -> &'a SendModeEnum {
+        self.providers.get(kind).map_or(kind, |provider| &provider.template_namespace)
+    }
+
+    pub fn providers(&self) -> impl Iterator<Item = &Provider> {
+        self.providers.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_provider_falls_back() {
+        let kind: SendModeEnum = serde_json::from_str(r#""PAYFLOW""#).unwrap();
+        assert_eq!(kind, SendModeEnum::Unknown("PAYFLOW".to_owned()));
+        assert_eq!(serde_json::to_string(&kind).unwrap(), r#""PAYFLOW""#);
+
+        let mut registry = ProviderRegistry::default();
+        assert!(!registry.is_registered(&kind));
+        assert!(!registry.capabilities(&kind).needs_rsa_key);
+        assert_eq!(registry.get(&kind).name(), "PAYFLOW");
+        assert!(registry.capabilities(&SendModeEnum::KRAFT).supports(&EventType::SMS));
+
+        let capabilities = registry.capabilities(&SendModeEnum::KRAFT).clone();
+        registry.register(Provider::new(kind.clone(), capabilities).with_template_namespace(SendModeEnum::KRAFT));
+        assert_eq!(registry.template_namespace(&kind), &SendModeEnum::KRAFT);
+    }
+}
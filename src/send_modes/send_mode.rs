@@ -10,14 +10,29 @@ use deadpool_redis::redis::{FromRedisValue, RedisError, RedisResult, RedisWrite,
 use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
 use rsa::RsaPrivateKey;
 use serde::{Deserialize, Serialize, Serializer};
-use tracing::{error};
+use tracing::{error, warn};
 use bytes::buf::BufMut;
 use reqwest::Body;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum SendModeEnum {
     KRAFT,
-    TRADEMO
+    TRADEMO,
+    Unknown(String),
+}
+
+impl SendModeEnum {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SendModeEnum::KRAFT => "KRAFT",
+            SendModeEnum::TRADEMO => "TRADEMO",
+            SendModeEnum::Unknown(name) => name,
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        !matches!(self, SendModeEnum::Unknown(_))
+    }
 }
 
 impl ToSql for SendModeEnum {
@@ -25,11 +40,7 @@ impl ToSql for SendModeEnum {
     where
         Self: Sized
     {
-        match self {
-            SendModeEnum::KRAFT => out.put(&b"KRAFT"[..]),
-
-            SendModeEnum::TRADEMO => out.put(&b"TRADEMO"[..]),
-        }
+        out.put(self.as_str().as_bytes());
         Ok(IsNull::No)
     }
 
@@ -40,24 +51,18 @@ impl ToSql for SendModeEnum {
         matches!(*ty, Type::VARCHAR | Type::TEXT)
     }
 
-    fn to_sql_checked(&self, _ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
-        match self {
-            SendModeEnum::KRAFT => out.put(&b"KRAFT"[..]),
-
-            SendModeEnum::TRADEMO => out.put(&b"TRADEMO"[..]),
-        }
-        Ok(IsNull::No)
+    fn to_sql_checked(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.to_sql(ty, out)
     }
 }
 
 impl Display for SendModeEnum {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SendModeEnum::KRAFT => write!(f, "KRAFT"),
-            SendModeEnum::TRADEMO => write!(f, "TRADEMO")
-        }
+        write!(f, "{}", self.as_str())
     }
 }
+
+/// Strict, unknown names are an error. Use [`From<&str>`] to keep them.
 impl FromStr for SendModeEnum {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -69,13 +74,18 @@ impl FromStr for SendModeEnum {
     }
 }
 
+impl From<&str> for SendModeEnum {
+    fn from(s: &str) -> Self {
+        SendModeEnum::from_str(s).unwrap_or_else(|_| {
+            warn!(send_mode=s, "Unknown send mode provider");
+            SendModeEnum::Unknown(s.to_owned())
+        })
+    }
+}
+
 impl FromSql<'_> for SendModeEnum {
-    fn from_sql<'a>(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
-        match raw {
-            b"KRAFT" => Ok(SendModeEnum::KRAFT),
-            b"TRADEMO" => Ok(SendModeEnum::TRADEMO),
-            _ => Err("Invalid parameter".into())
-        }
+    fn from_sql(_ty: &Type, raw: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(SendModeEnum::from(std::str::from_utf8(raw)?))
     }
 
     fn accepts(ty: &Type) -> bool {
@@ -83,6 +93,25 @@ impl FromSql<'_> for SendModeEnum {
     }
 }
 
+impl Serialize for SendModeEnum {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SendModeEnum {
+    fn deserialize<D>(deserializer: D) -> Result<SendModeEnum, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(SendModeEnum::from(s.as_str()))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SendMode {
     pub id: String,
@@ -192,11 +221,32 @@ pub struct NewSendModeRequest {
     pub mode: SendModeEnum,
     pub access_token: String,
     pub auto_heartbeat_interval: Option<i32>,
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
 }
 
-impl Into<Body> for NewSendModeRequest {
-    fn into(self) -> Body {
-        reqwest::Body::from(simd_json::to_vec(&self).unwrap())
+impl NewSendModeRequest {
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    pub fn ensure_idempotency_key(&mut self) -> &str {
+        self.idempotency_key.get_or_insert_with(|| uuid::Uuid::new_v4().to_string())
+    }
+
+    /// The access token tells a repeated request apart from a mode created before under the same name.
+    pub fn is_created_as(&self, send_mode: &SendMode) -> bool {
+        send_mode.aggregate_id == self.aggregate_id
+            && send_mode.name == self.name
+            && send_mode.send_mode == self.mode
+            && send_mode.access_token == self.access_token
+    }
+}
+
+impl From<&NewSendModeRequest> for Body {
+    fn from(request: &NewSendModeRequest) -> Self {
+        reqwest::Body::from(simd_json::to_vec(request).unwrap())
     }
 }
 
@@ -204,4 +254,80 @@ impl Into<Body> for NewSendModeRequest {
 pub struct RenameSendModeRequest {
     pub id: String,
     pub name: String
-}
\ No newline at end of file
+}
+
+impl From<&RenameSendModeRequest> for Body {
+    fn from(request: &RenameSendModeRequest) -> Self {
+        reqwest::Body::from(simd_json::to_vec(request).unwrap())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateSendModeRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_heartbeat_interval: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<String>,
+}
+
+impl From<&UpdateSendModeRequest> for Body {
+    fn from(request: &UpdateSendModeRequest) -> Self {
+        reqwest::Body::from(simd_json::to_vec(request).unwrap())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListSendModesRequest {
+    pub limit: u32,
+    pub offset: u32,
+}
+
+impl Default for ListSendModesRequest {
+    fn default() -> Self {
+        Self { limit: 100, offset: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendModePage {
+    pub send_modes: Vec<SendMode>,
+    pub total: u64,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+impl SendModePage {
+    pub fn next_page(&self) -> Option<ListSendModesRequest> {
+        let next_offset = self.offset as u64 + self.send_modes.len() as u64;
+        if self.send_modes.is_empty() || next_offset >= self.total {
+            return None
+        }
+        Some(ListSendModesRequest { limit: self.limit, offset: next_offset as u32 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send_modes::fixtures;
+
+    fn page(len: usize, total: u64, offset: u32) -> SendModePage {
+        let send_mode = fixtures::send_mode("id");
+        SendModePage { send_modes: vec![send_mode; len], total, limit: 2, offset }
+    }
+
+    fn next(page: &SendModePage) -> Option<(u32, u32)> {
+        page.next_page().map(|request| (request.limit, request.offset))
+    }
+
+    #[test]
+    fn next_page_stops_at_last_and_empty_page() {
+        assert_eq!(next(&page(2, 5, 0)), Some((2, 2)));
+        assert_eq!(next(&page(2, 5, 2)), Some((2, 4)));
+        assert_eq!(next(&page(1, 5, 4)), None);
+        assert_eq!(next(&page(2, 4, 2)), None);
+        // A stale total, e.g. after deletes, does not keep an empty page going
+        assert_eq!(next(&page(0, 5, 4)), None);
+        assert_eq!(next(&page(0, 0, 0)), None);
+    }
+}
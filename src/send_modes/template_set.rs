@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::{error, warn};
+use crate::send_modes::error::ParseError;
+use crate::send_modes::event::{Event, TextMessage};
+use crate::send_modes::money::MoneyRules;
+use crate::send_modes::notification_types::NotificationTemplate;
+use crate::send_modes::parser::{extract_first, CompiledTemplate};
+use crate::send_modes::provider::Provider;
+use crate::send_modes::send_mode::SendModeEnum;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TemplateKey {
+    source: String,
+    notification_type: String,
+    send_mode: SendModeEnum,
+}
+
+#[derive(Debug, Default)]
+pub struct TemplateSet {
+    templates: Vec<CompiledTemplate>,
+    positions: Vec<usize>,
+    index: HashMap<TemplateKey, Vec<usize>>,
+    rejected: Vec<RejectedTemplate>,
+}
+
+#[derive(Debug)]
+pub struct RejectedTemplate {
+    pub template: NotificationTemplate,
+    pub error: ParseError,
+}
+
+impl TemplateSet {
+    pub fn new(templates: Vec<NotificationTemplate>) -> Self {
+        Self::with_rules(templates, &MoneyRules::default())
+    }
+
+    pub fn with_rules(templates: Vec<NotificationTemplate>, rules: &MoneyRules) -> Self {
+        let mut set = Self::default();
+        for (position, template) in templates.into_iter().enumerate() {
+            match CompiledTemplate::compile_with_rules(template.clone(), rules) {
+                Ok(compiled) => set.push(position, compiled),
+                Err(error) => {
+                    warn!(bank=template.bank, source=template.source, err=error.to_string(), "Template skipped");
+                    set.rejected.push(RejectedTemplate { template, error });
+                }
+            }
+        }
+        // Stable, so templates of equal priority keep their order
+        let templates = &set.templates;
+        for ids in set.index.values_mut() {
+            ids.sort_by_key(|&id| std::cmp::Reverse(templates[id].template().priority));
+        }
+        set
+    }
+
+    pub fn rejected(&self) -> &[RejectedTemplate] {
+        &self.rejected
+    }
+
+    fn push(&mut self, position: usize, template: CompiledTemplate) {
+        let key = TemplateKey {
+            source: template.template().source.clone(),
+            notification_type: template.template().notification_type.clone(),
+            send_mode: template.template().send_mode.clone(),
+        };
+        self.index.entry(key).or_default().push(self.templates.len());
+        self.templates.push(template);
+        self.positions.push(position);
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = Vec<&CompiledTemplate>> {
+        let mut groups: Vec<_> = self.index.iter().collect();
+        // Send modes of a source and channel come in the order their first template was added
+        groups.sort_by_key(|(key, ids)| (&key.source, &key.notification_type, ids.iter().min()));
+        groups.into_iter().map(|(_, ids)| ids.iter().map(|&id| &self.templates[id]).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    pub fn candidates<'a>(&'a self, message: &TextMessage, send_mode: Option<&'a SendModeEnum>)
+        -> impl Iterator<Item = &'a CompiledTemplate> + 'a
+    {
+        self.positioned_candidates(message, send_mode).map(|(_, template)| template)
+    }
+
+    pub fn positioned_candidates<'a>(&'a self, message: &TextMessage, send_mode: Option<&'a SendModeEnum>)
+        -> impl Iterator<Item = (usize, &'a CompiledTemplate)> + 'a
+    {
+        let notification_type = message.event_type.to_string();
+        let of_mode = send_mode.and_then(|send_mode| self.index.get(&TemplateKey {
+            source: message.source.clone(),
+            notification_type: notification_type.clone(),
+            send_mode: send_mode.clone(),
+        }));
+        let of_any_mode = send_mode.is_none().then(|| {
+            let mut ids: Vec<usize> = self.index.iter()
+                .filter(|(key, _)| key.source == message.source && key.notification_type == notification_type)
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+            ids.sort_by_key(|&id| (std::cmp::Reverse(self.templates[id].template().priority), id));
+            ids
+        });
+        of_mode.into_iter().flatten().copied()
+            .chain(of_any_mode.into_iter().flatten())
+            .map(|id| (self.positions[id], &self.templates[id]))
+    }
+
+    pub fn explain<'a>(&'a self, message: &TextMessage, send_mode: Option<&'a SendModeEnum>) -> Vec<Explanation<'a>> {
+        let mut matched = false;
+        self.candidates(message, send_mode)
+            .map(|template| {
+                let verdict = if !template.regex_matches(&message.text) {
+                    Verdict::NoMatch
+                } else if let Some(word) = template.excluded_by(&message.text) {
+                    Verdict::Excluded(word.to_owned())
+                } else if matched {
+                    Verdict::Shadowed
+                } else {
+                    matched = true;
+                    Verdict::Matched
+                };
+                Explanation { template, verdict }
+            })
+            .collect()
+    }
+
+    pub fn parse(&self, message: &TextMessage, send_mode: &SendModeEnum) -> Result<Event, ParseError> {
+        self.parse_candidates(message, Some(send_mode))
+    }
+
+    pub fn parse_for(&self, message: &TextMessage, provider: &Provider) -> Result<Event, ParseError> {
+        self.parse_candidates(message, Some(&provider.template_namespace))
+    }
+
+    pub fn parse_any(&self, message: &TextMessage) -> Result<Event, ParseError> {
+        self.parse_candidates(message, None)
+    }
+
+    fn parse_candidates(&self, message: &TextMessage, send_mode: Option<&SendModeEnum>) -> Result<Event, ParseError> {
+        extract_first(message, self.candidates(message, send_mode))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Matched,
+    Shadowed,
+    Excluded(String),
+    NoMatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct Explanation<'a> {
+    pub template: &'a CompiledTemplate,
+    pub verdict: Verdict,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SharedTemplateSet {
+    inner: Arc<RwLock<Arc<TemplateSet>>>,
+    rules: Arc<MoneyRules>,
+}
+
+impl SharedTemplateSet {
+    pub fn new(set: TemplateSet) -> Self {
+        Self { inner: Arc::new(RwLock::new(Arc::new(set))), rules: Arc::default() }
+    }
+
+    /// Rules [`SharedTemplateSet::reload`] compiles with, the set given to `new` should use the same.
+    pub fn with_rules(mut self, rules: MoneyRules) -> Self {
+        self.rules = Arc::new(rules);
+        self
+    }
+
+    pub fn load(&self) -> Arc<TemplateSet> {
+        match self.inner.read() {
+            Ok(set) => set.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn store(&self, set: TemplateSet) {
+        let set = Arc::new(set);
+        match self.inner.write() {
+            Ok(mut current) => *current = set,
+            Err(poisoned) => *poisoned.into_inner() = set,
+        }
+    }
+
+    pub fn reload(&self, templates: Vec<NotificationTemplate>) -> usize {
+        let set = TemplateSet::with_rules(templates, &self.rules);
+        let rejected = set.rejected().len();
+        if rejected > 0 {
+            error!(rejected=rejected, "Template set reloaded with rejected templates");
+        }
+        self.store(set);
+        rejected
+    }
+
+    pub fn parse(&self, message: &TextMessage, send_mode: &SendModeEnum) -> Result<Event, ParseError> {
+        self.load().parse(message, send_mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send_modes::event::EventType;
+    use crate::send_modes::fixtures;
+    use crate::send_modes::money::AmountFormat;
+
+    fn template(source: &str, send_mode: SendModeEnum, text: &str) -> NotificationTemplate {
+        NotificationTemplate { bank: source.to_owned(), send_mode, source: source.to_owned(), ..fixtures::template(text) }
+    }
+
+    fn message(source: &str, text: &str) -> TextMessage {
+        TextMessage {
+            mode_id: "mode".to_owned(),
+            source: source.to_owned(),
+            text: text.to_owned(),
+            event_type: EventType::SMS,
+            subject: None,
+            sender: None,
+        }
+    }
+
+    #[test]
+    fn dispatches_by_source_and_send_mode() {
+        let set = TemplateSet::new(vec![
+            template("900", SendModeEnum::KRAFT, "Зачисление {amount}р"),
+            template("900", SendModeEnum::TRADEMO, "Перевод {amount}р"),
+            template("tinkoff", SendModeEnum::KRAFT, "Пополнение {amount}р"),
+        ]);
+        let msg = message("900", "Перевод 10р");
+        assert_eq!(set.candidates(&msg, Some(&SendModeEnum::KRAFT)).count(), 1);
+        assert!(set.parse(&msg, &SendModeEnum::KRAFT).is_err());
+        assert_eq!(set.parse(&msg, &SendModeEnum::TRADEMO).unwrap().bank, "900");
+    }
+
+    #[test]
+    fn merges_send_modes_by_priority_without_send_mode() {
+        let mut urgent = template("900", SendModeEnum::TRADEMO, "{*} {amount}р");
+        urgent.priority = 1;
+        let set = TemplateSet::new(vec![
+            template("900", SendModeEnum::KRAFT, "Зачисление {amount}р"),
+            template("900", SendModeEnum::TRADEMO, "Зачисление {amount}р"),
+            template("900", SendModeEnum::KRAFT, "{*} {amount}р"),
+            urgent,
+        ]);
+        let msg = message("900", "Зачисление 10р");
+        let order = |send_mode| set.positioned_candidates(&msg, send_mode).map(|(position, _)| position).collect::<Vec<_>>();
+        assert_eq!(order(Some(&SendModeEnum::KRAFT)), vec![0, 2]);
+        assert_eq!(order(Some(&SendModeEnum::TRADEMO)), vec![3, 1]);
+        assert_eq!(order(None), vec![3, 0, 1, 2]);
+        assert_eq!(set.groups().count(), 2);
+    }
+
+    #[test]
+    fn orders_by_priority_then_insertion() {
+        let mut urgent = template("900", SendModeEnum::KRAFT, "{*} {amount}р");
+        urgent.priority = 1;
+        let set = TemplateSet::new(vec![
+            template("900", SendModeEnum::KRAFT, "{*} {amount}р"),
+            template("900", SendModeEnum::KRAFT, "Зачисление {amount}р"),
+            urgent,
+        ]);
+        let msg = message("900", "Зачисление 10р");
+        let order: Vec<_> = set.positioned_candidates(&msg, None).map(|(position, _)| position).collect();
+        assert_eq!(order, vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn skips_invalid_templates() {
+        let mut new_channel = template("900", SendModeEnum::KRAFT, "Письмо {amount}р");
+        new_channel.notification_type = "carrier_pigeon".to_owned();
+        let set = TemplateSet::new(vec![
+            template("900", SendModeEnum::KRAFT, "Зачисление {amount}р"),
+            new_channel,
+            template("900", SendModeEnum::KRAFT, "Без суммы"),
+        ]);
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.rejected().len(), 2);
+        assert!(set.parse(&message("900", "Зачисление 10р"), &SendModeEnum::KRAFT).is_ok());
+    }
+
+    #[test]
+    fn reload_swaps_set() {
+        let shared = SharedTemplateSet::default();
+        let msg = message("900", "Зачисление 10р");
+        assert!(shared.parse(&msg, &SendModeEnum::KRAFT).is_err());
+        shared.reload(vec![template("900", SendModeEnum::KRAFT, "Зачисление {amount}р")]);
+        assert_eq!(shared.parse(&msg, &SendModeEnum::KRAFT).unwrap().amount.amount.to_string(), "10.00");
+
+        let whole = AmountFormat { scale: Some(0), ..AmountFormat::default() };
+        let shared = SharedTemplateSet::default().with_rules(MoneyRules::default().with_bank("900", whole));
+        shared.reload(vec![template("900", SendModeEnum::KRAFT, "Зачисление {amount}р")]);
+        assert_eq!(shared.parse(&msg, &SendModeEnum::KRAFT).unwrap().amount.amount.to_string(), "10");
+    }
+}
@@ -0,0 +1,358 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+use deadpool_redis::redis::streams::{
+    StreamClaimReply, StreamId, StreamMaxlen, StreamPendingCountReply, StreamPendingId, StreamReadOptions,
+    StreamReadReply,
+};
+use deadpool_redis::redis::{self, AsyncCommands};
+use deadpool_redis::{Connection, Pool};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::{error, info, warn};
+use crate::ingest::EventPublisher;
+use crate::send_modes::error::LibError;
+use crate::send_modes::event::{Event, TextMessage};
+
+pub const PAYLOAD_FIELD: &str = "payload";
+pub const REASON_FIELD: &str = "reason";
+pub const ORIGIN_ID_FIELD: &str = "origin_id";
+
+pub type TextMessageProducer = StreamProducer<TextMessage>;
+pub type TextMessageConsumer = StreamConsumer<TextMessage>;
+pub type EventProducer = StreamProducer<Event>;
+pub type EventConsumer = StreamConsumer<Event>;
+
+async fn connection(pool: &Pool) -> Result<Connection, LibError> {
+    pool.get().await.map_err(|e| redis_error(e.to_string()))
+}
+
+fn redis_error(e: String) -> LibError {
+    error!(err=e, "Redis stream error");
+    LibError::RedisError(e)
+}
+
+pub struct StreamProducer<T> {
+    pool: Pool,
+    stream: String,
+    max_len: Option<usize>,
+    _payload: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize> StreamProducer<T> {
+    pub fn new(pool: Pool, stream: impl Into<String>) -> Self {
+        Self { pool, stream: stream.into(), max_len: None, _payload: PhantomData }
+    }
+
+    /// Trims the stream to about `max_len` entries, the oldest ones are dropped even if not acked.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    pub async fn publish(&self, value: &T) -> Result<String, LibError> {
+        let payload = serde_json::to_string(value).map_err(|e| {
+            error!(err=e.to_string(), "Stream payload serialize error");
+            LibError::InternalServerError
+        })?;
+        let fields = [(PAYLOAD_FIELD, payload)];
+        let mut conn = connection(&self.pool).await?;
+        let id = match self.max_len {
+            Some(max_len) => conn.xadd_maxlen(&self.stream, StreamMaxlen::Approx(max_len), "*", &fields).await,
+            None => conn.xadd(&self.stream, "*", &fields).await,
+        };
+        id.map_err(|e| redis_error(e.to_string()))
+    }
+}
+
+impl EventPublisher for StreamProducer<Event> {
+    async fn publish(&self, event: &Event) -> Result<(), LibError> {
+        StreamProducer::publish(self, event).await.map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConsumerConfig {
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    pub batch: usize,
+    pub block: Duration,
+    pub min_idle: Duration,
+    pub max_deliveries: usize,
+    pub dead_letter: String,
+}
+
+impl ConsumerConfig {
+    pub fn new(stream: impl Into<String>, group: impl Into<String>, consumer: impl Into<String>) -> Self {
+        let stream = stream.into();
+        Self {
+            dead_letter: format!("{stream}:dead_letter"),
+            stream,
+            group: group.into(),
+            consumer: consumer.into(),
+            batch: 100,
+            block: Duration::from_secs(5),
+            min_idle: Duration::from_secs(60),
+            max_deliveries: 5,
+        }
+    }
+}
+
+/// Must be acked or dead-lettered once handled.
+#[derive(Debug, Clone)]
+pub struct Delivery<T> {
+    pub id: String,
+    pub payload: T,
+    pub raw: String,
+}
+
+pub struct StreamConsumer<T> {
+    pool: Pool,
+    config: ConsumerConfig,
+    _payload: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> StreamConsumer<T> {
+    pub fn new(pool: Pool, config: ConsumerConfig) -> Self {
+        Self { pool, config, _payload: PhantomData }
+    }
+
+    pub fn config(&self) -> &ConsumerConfig {
+        &self.config
+    }
+
+    pub async fn ensure_group(&self) -> Result<(), LibError> {
+        let mut conn = connection(&self.pool).await?;
+        let created: redis::RedisResult<()> = conn.xgroup_create_mkstream(&self.config.stream, &self.config.group, "$").await;
+        match created {
+            Ok(()) => {
+                info!(stream=self.config.stream, group=self.config.group, "Consumer group created");
+                Ok(())
+            }
+            Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+            Err(e) => Err(redis_error(e.to_string())),
+        }
+    }
+
+    pub async fn read(&self) -> Result<Vec<Delivery<T>>, LibError> {
+        let options = StreamReadOptions::default()
+            .group(&self.config.group, &self.config.consumer)
+            .count(self.config.batch)
+            .block(self.config.block.as_millis() as usize);
+        let mut conn = connection(&self.pool).await?;
+        let reply: Option<StreamReadReply> = conn.xread_options(&[&self.config.stream], &[">"], &options).await
+            .map_err(|e| redis_error(e.to_string()))?;
+        let entries = reply.into_iter().flat_map(|reply| reply.keys).flat_map(|key| key.ids).collect();
+        self.decode_all(&mut conn, entries).await
+    }
+
+    pub async fn reclaim(&self) -> Result<Vec<Delivery<T>>, LibError> {
+        let mut conn = connection(&self.pool).await?;
+        let pending: StreamPendingCountReply = conn
+            .xpending_count(&self.config.stream, &self.config.group, "-", "+", self.config.batch)
+            .await
+            .map_err(|e| redis_error(e.to_string()))?;
+        let min_idle = self.config.min_idle.as_millis() as usize;
+        let (exhausted, retry) = partition_pending(pending.ids, min_idle, self.config.max_deliveries);
+
+        for (ids, dead) in [(exhausted, true), (retry, false)] {
+            if ids.is_empty() {
+                continue
+            }
+            let claimed: StreamClaimReply = conn
+                .xclaim(&self.config.stream, &self.config.group, &self.config.consumer, min_idle, &ids)
+                .await
+                .map_err(|e| redis_error(e.to_string()))?;
+            if dead {
+                for entry in claimed.ids {
+                    let raw = entry.get::<String>(PAYLOAD_FIELD).unwrap_or_default();
+                    self.move_to_dead_letter(&mut conn, &entry.id, &raw, "too many deliveries").await?;
+                }
+            } else {
+                info!(stream=self.config.stream, count=claimed.ids.len(), "Pending entries reclaimed");
+                return self.decode_all(&mut conn, claimed.ids).await
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    pub async fn ack(&self, ids: &[String]) -> Result<(), LibError> {
+        if ids.is_empty() {
+            return Ok(())
+        }
+        let mut conn = connection(&self.pool).await?;
+        conn.xack::<_, _, _, ()>(&self.config.stream, &self.config.group, ids).await
+            .map_err(|e| redis_error(e.to_string()))
+    }
+
+    pub async fn dead_letter(&self, delivery: &Delivery<T>, reason: &str) -> Result<(), LibError> {
+        let mut conn = connection(&self.pool).await?;
+        self.move_to_dead_letter(&mut conn, &delivery.id, &delivery.raw, reason).await
+    }
+
+    async fn move_to_dead_letter(&self, conn: &mut Connection, id: &str, raw: &str, reason: &str) -> Result<(), LibError> {
+        warn!(stream=self.config.stream, id=id, reason=reason, "Entry dead-lettered");
+        let fields = [(PAYLOAD_FIELD, raw), (REASON_FIELD, reason), (ORIGIN_ID_FIELD, id)];
+        // One MULTI/EXEC, the entry is either moved and acked or stays pending
+        redis::pipe()
+            .atomic()
+            .xadd(&self.config.dead_letter, "*", &fields).ignore()
+            .xack(&self.config.stream, &self.config.group, &[id]).ignore()
+            .query_async::<()>(conn)
+            .await
+            .map_err(|e| redis_error(e.to_string()))
+    }
+
+    async fn decode_all(&self, conn: &mut Connection, entries: Vec<StreamId>) -> Result<Vec<Delivery<T>>, LibError> {
+        let mut deliveries = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match decode(&entry) {
+                Ok(delivery) => deliveries.push(delivery),
+                Err(reason) => {
+                    let raw = entry.get::<String>(PAYLOAD_FIELD).unwrap_or_default();
+                    self.move_to_dead_letter(conn, &entry.id, &raw, &reason).await?;
+                }
+            }
+        }
+        Ok(deliveries)
+    }
+}
+
+fn partition_pending(pending: Vec<StreamPendingId>, min_idle: usize, max_deliveries: usize) -> (Vec<String>, Vec<String>) {
+    let (exhausted, retry): (Vec<_>, Vec<_>) = pending.into_iter()
+        .filter(|entry| entry.last_delivered_ms >= min_idle)
+        .partition(|entry| entry.times_delivered >= max_deliveries);
+    let ids = |entries: Vec<StreamPendingId>| entries.into_iter().map(|entry| entry.id).collect();
+    (ids(exhausted), ids(retry))
+}
+
+fn decode<T: DeserializeOwned>(entry: &StreamId) -> Result<Delivery<T>, String> {
+    let raw: String = entry.get(PAYLOAD_FIELD).ok_or_else(|| format!("missing {PAYLOAD_FIELD} field"))?;
+    let payload = serde_json::from_str(&raw).map_err(|e| format!("invalid payload: {e}"))?;
+    Ok(Delivery { id: entry.id.clone(), payload, raw })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use deadpool_redis::redis::Value;
+    use super::*;
+    use crate::send_modes::event::EventType;
+    use crate::tools::fake_redis::FakeRedis;
+
+    fn entry(payload: &str) -> StreamId {
+        StreamId {
+            id: "1-0".to_owned(),
+            map: HashMap::from([(PAYLOAD_FIELD.to_owned(), Value::BulkString(payload.as_bytes().to_vec()))]),
+        }
+    }
+
+    #[test]
+    fn decodes_payload_and_reports_poison() {
+        let delivery: Delivery<TextMessage> = decode(
+            &entry(r#"{"mode_id": "1", "source": "900", "text": "text", "event_type": "sms"}"#)
+        ).unwrap();
+        assert_eq!(delivery.payload.event_type, EventType::SMS);
+        assert!(decode::<TextMessage>(&entry("{")).unwrap_err().starts_with("invalid payload"));
+        assert!(decode::<TextMessage>(&StreamId::default()).is_err());
+    }
+
+    #[test]
+    fn partitions_pending_by_deliveries() {
+        let pending = |id: &str, idle: usize, deliveries: usize| StreamPendingId {
+            id: id.to_owned(),
+            consumer: "crashed".to_owned(),
+            last_delivered_ms: idle,
+            times_delivered: deliveries,
+        };
+        let (exhausted, retry) = partition_pending(vec![
+            pending("1-0", 60_000, 5),
+            pending("2-0", 60_000, 4),
+            pending("3-0", 59_999, 9),
+            pending("4-0", 120_000, 6),
+            pending("5-0", 60_000, 1),
+        ], 60_000, 5);
+        assert_eq!(exhausted, vec!["1-0", "4-0"]);
+        assert_eq!(retry, vec!["2-0", "5-0"]);
+    }
+
+    fn message(text: &str) -> TextMessage {
+        serde_json::from_str(&format!(r#"{{"mode_id": "1", "source": "900", "text": "{text}", "event_type": "sms"}}"#)).unwrap()
+    }
+
+    fn consumer(redis: &FakeRedis, name: &str, max_deliveries: usize) -> TextMessageConsumer {
+        let mut config = ConsumerConfig::new("messages", "ingest", name);
+        config.block = Duration::ZERO;
+        config.min_idle = Duration::ZERO;
+        config.max_deliveries = max_deliveries;
+        StreamConsumer::new(redis.pool(), config)
+    }
+
+    #[tokio::test]
+    async fn reads_and_acks() {
+        let redis = FakeRedis::start().await;
+        let consumer = consumer(&redis, "a", 5);
+        consumer.ensure_group().await.unwrap();
+        consumer.ensure_group().await.unwrap();
+        let id = TextMessageProducer::new(redis.pool(), "messages").publish(&message("first")).await.unwrap();
+
+        let deliveries = consumer.read().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].id, id);
+        assert_eq!(deliveries[0].payload.text, "first");
+        assert_eq!(redis.pending("messages", "ingest"), vec![id.clone()]);
+        assert!(consumer.read().await.unwrap().is_empty());
+
+        consumer.ack(&[id]).await.unwrap();
+        assert!(redis.pending("messages", "ingest").is_empty());
+    }
+
+    #[tokio::test]
+    async fn dead_letters_poison_and_unmatched_entries() {
+        let redis = FakeRedis::start().await;
+        let consumer = consumer(&redis, "a", 5);
+        consumer.ensure_group().await.unwrap();
+        let poison = StreamProducer::<&str>::new(redis.pool(), "messages").publish(&"poison").await.unwrap();
+        let unmatched = TextMessageProducer::new(redis.pool(), "messages").publish(&message("unmatched")).await.unwrap();
+
+        let deliveries = consumer.read().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        consumer.dead_letter(&deliveries[0], "no template").await.unwrap();
+
+        let dead = redis.stream("messages:dead_letter");
+        assert_eq!(dead.len(), 2);
+        assert_eq!(dead[0].1[ORIGIN_ID_FIELD], poison);
+        assert_eq!(dead[0].1[PAYLOAD_FIELD], r#""poison""#);
+        assert!(dead[0].1[REASON_FIELD].starts_with("invalid payload"));
+        assert_eq!(dead[1].1[ORIGIN_ID_FIELD], unmatched);
+        assert_eq!(dead[1].1[REASON_FIELD], "no template");
+        assert!(redis.pending("messages", "ingest").is_empty());
+    }
+
+    #[tokio::test]
+    async fn reclaims_then_dead_letters_exhausted_entries() {
+        let redis = FakeRedis::start().await;
+        let crashed = consumer(&redis, "crashed", 2);
+        let survivor = consumer(&redis, "survivor", 2);
+        crashed.ensure_group().await.unwrap();
+        let id = TextMessageProducer::new(redis.pool(), "messages").publish(&message("stuck")).await.unwrap();
+        assert_eq!(crashed.read().await.unwrap().len(), 1);
+
+        // Second delivery, taken over from the crashed consumer
+        let reclaimed = survivor.reclaim().await.unwrap();
+        assert_eq!(reclaimed.len(), 1);
+        assert_eq!(reclaimed[0].id, id);
+        assert_eq!(reclaimed[0].payload.text, "stuck");
+        assert!(redis.stream("messages:dead_letter").is_empty());
+
+        // Delivered max_deliveries times and still pending
+        assert!(survivor.reclaim().await.unwrap().is_empty());
+        let dead = redis.stream("messages:dead_letter");
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].1[ORIGIN_ID_FIELD], id);
+        assert_eq!(dead[0].1[REASON_FIELD], "too many deliveries");
+        assert_eq!(dead[0].1[PAYLOAD_FIELD], reclaimed[0].raw);
+        assert!(redis.pending("messages", "ingest").is_empty());
+        assert!(survivor.reclaim().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+//! In-memory Redis speaking just enough RESP2 for the unit tests, expiry is not enforced.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use deadpool_redis::{Config, Pool, PoolConfig, Runtime};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(String),
+    List(Vec<String>),
+    Stream(Stream),
+}
+
+type StreamEntry = (StreamEntryId, Vec<(String, String)>);
+
+/// `ms-seq` id of a stream entry, the fake generates `n-0`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct StreamEntryId(u64, u64);
+
+impl StreamEntryId {
+    fn parse(id: &str) -> Option<Self> {
+        let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+        Some(Self(ms.parse().ok()?, seq.parse().ok()?))
+    }
+}
+
+impl std::fmt::Display for StreamEntryId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.0, self.1)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Stream {
+    entries: Vec<StreamEntry>,
+    last_id: StreamEntryId,
+    groups: HashMap<String, Group>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Group {
+    last_delivered: StreamEntryId,
+    pending: BTreeMap<StreamEntryId, Pending>,
+}
+
+#[derive(Debug, Clone)]
+struct Pending {
+    consumer: String,
+    delivered_at: Instant,
+    deliveries: usize,
+}
+
+#[derive(Default)]
+struct Store {
+    data: HashMap<String, Value>,
+    /// Bumped on every write of a key, `WATCH` compares it.
+    versions: HashMap<String, u64>,
+}
+
+impl Store {
+    fn touch(&mut self, key: &str) {
+        *self.versions.entry(key.to_owned()).or_default() += 1;
+    }
+
+    fn version(&self, key: &str) -> u64 {
+        self.versions.get(key).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    watched: Vec<(String, u64)>,
+    queued: Option<Vec<Vec<String>>>,
+}
+
+#[derive(Clone)]
+pub struct FakeRedis {
+    store: Arc<Mutex<Store>>,
+    url: String,
+}
+
+impl FakeRedis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let redis = Self {
+            store: Arc::default(),
+            url: format!("redis://{}", listener.local_addr().unwrap()),
+        };
+        let server = redis.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(server.clone().serve(stream));
+            }
+        });
+        redis
+    }
+
+    pub fn pool(&self) -> Pool {
+        Config::from_url(&self.url).create_pool(Some(Runtime::Tokio1)).unwrap()
+    }
+
+    /// Pool of a single connection, to catch a connection held across an await.
+    pub fn single_connection_pool(&self) -> Pool {
+        let config = Config { pool: Some(PoolConfig::new(1)), ..Config::from_url(&self.url) };
+        config.create_pool(Some(Runtime::Tokio1)).unwrap()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.store.lock().unwrap().data.get(key) {
+            Some(Value::String(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn list(&self, key: &str) -> Vec<String> {
+        match self.store.lock().unwrap().data.get(key) {
+            Some(Value::List(values)) => values.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn stream(&self, key: &str) -> Vec<(String, HashMap<String, String>)> {
+        match self.store.lock().unwrap().data.get(key) {
+            Some(Value::Stream(stream)) => stream.entries.iter()
+                .map(|(id, fields)| (id.to_string(), fields.iter().cloned().collect()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn pending(&self, key: &str, group: &str) -> Vec<String> {
+        match self.store.lock().unwrap().data.get(key) {
+            Some(Value::Stream(stream)) => stream.groups.get(group)
+                .map(|group| group.pending.keys().map(|id| id.to_string()).collect())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        let mut store = self.store.lock().unwrap();
+        store.data.insert(key.to_owned(), Value::String(value.to_owned()));
+        store.touch(key);
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        // Replies to pipelined commands are written one by one, Nagle would delay them
+        let _ = stream.set_nodelay(true);
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut session = Session::default();
+        while let Some(command) = read_command(&mut reader).await {
+            let reply = self.handle(&mut session, command);
+            if writer.write_all(reply.as_bytes()).await.is_err() {
+                return
+            }
+        }
+    }
+
+    fn handle(&self, session: &mut Session, command: Vec<String>) -> String {
+        let name = command[0].to_uppercase();
+        match name.as_str() {
+            "MULTI" => {
+                session.queued = Some(Vec::new());
+                "+OK\r\n".to_owned()
+            }
+            "EXEC" => {
+                let queued = session.queued.take().unwrap_or_default();
+                let mut store = self.store.lock().unwrap();
+                let watched = std::mem::take(&mut session.watched);
+                if watched.iter().any(|(key, version)| store.version(key) != *version) {
+                    return "*-1\r\n".to_owned()
+                }
+                let replies: String = queued.iter().map(|command| execute(&mut store, command)).collect();
+                format!("*{}\r\n{}", queued.len(), replies)
+            }
+            _ if session.queued.is_some() => {
+                session.queued.as_mut().unwrap().push(command);
+                "+QUEUED\r\n".to_owned()
+            }
+            "WATCH" => {
+                let store = self.store.lock().unwrap();
+                session.watched.extend(command[1..].iter().map(|key| (key.clone(), store.version(key))));
+                "+OK\r\n".to_owned()
+            }
+            "UNWATCH" => {
+                session.watched.clear();
+                "+OK\r\n".to_owned()
+            }
+            _ => execute(&mut self.store.lock().unwrap(), &command),
+        }
+    }
+}
+
+fn execute(store: &mut Store, command: &[String]) -> String {
+    let args = &command[1..];
+    match command[0].to_uppercase().as_str() {
+        "GET" => match store.data.get(&args[0]) {
+            Some(Value::String(value)) => bulk(Some(value)),
+            Some(_) => wrong_type(),
+            None => bulk(None),
+        },
+        "SET" => {
+            let flags: Vec<String> = args[2..].iter().map(|a| a.to_uppercase()).collect();
+            let has = |flag: &str| flags.iter().any(|f| f == flag);
+            let expiry = flags.iter().position(|f| f == "EX" || f == "PX").map(|i| &flags[i + 1]);
+            if expiry.is_some_and(|expiry| expiry.parse::<i64>().is_ok_and(|e| e <= 0)) {
+                return invalid_expire()
+            }
+            let previous = match store.data.get(&args[0]) {
+                Some(Value::String(value)) => Some(value.clone()),
+                Some(_) => return wrong_type(),
+                None => None,
+            };
+            let skip = (has("NX") && previous.is_some()) || (has("XX") && previous.is_none());
+            if !skip {
+                store.data.insert(args[0].clone(), Value::String(args[1].clone()));
+                store.touch(&args[0]);
+            }
+            match (has("GET"), skip) {
+                (true, _) => bulk(previous.as_ref()),
+                (false, true) => bulk(None),
+                (false, false) => "+OK\r\n".to_owned(),
+            }
+        }
+        "SETEX" | "PSETEX" => {
+            if args[1].parse::<i64>().is_ok_and(|e| e <= 0) {
+                return invalid_expire()
+            }
+            store.data.insert(args[0].clone(), Value::String(args[2].clone()));
+            store.touch(&args[0]);
+            "+OK\r\n".to_owned()
+        }
+        "INCR" | "INCRBY" => {
+            let delta = args.get(1).and_then(|delta| delta.parse::<i64>().ok()).unwrap_or(1);
+            let value = match store.data.get(&args[0]) {
+                Some(Value::String(value)) => value.parse::<i64>().unwrap_or_default() + delta,
+                Some(_) => return wrong_type(),
+                None => delta,
+            };
+            store.data.insert(args[0].clone(), Value::String(value.to_string()));
+            store.touch(&args[0]);
+            format!(":{value}\r\n")
+        }
+        "DEL" => {
+            let mut removed = 0;
+            for key in args {
+                if store.data.remove(key).is_some() {
+                    store.touch(key);
+                    removed += 1;
+                }
+            }
+            format!(":{removed}\r\n")
+        }
+        "EXISTS" => format!(":{}\r\n", args.iter().filter(|key| store.data.contains_key(*key)).count()),
+        "EXPIRE" | "PEXPIRE" => {
+            let exists = store.data.contains_key(&args[0]);
+            // A non-positive timeout deletes the key
+            if exists && args[1].parse::<i64>().is_ok_and(|e| e <= 0) {
+                store.data.remove(&args[0]);
+                store.touch(&args[0]);
+            }
+            format!(":{}\r\n", exists as u8)
+        }
+        "RPUSH" => {
+            let list = store.data.entry(args[0].clone()).or_insert_with(|| Value::List(Vec::new()));
+            let Value::List(values) = list else {
+                return wrong_type()
+            };
+            values.extend(args[1..].iter().cloned());
+            let len = values.len();
+            store.touch(&args[0]);
+            format!(":{len}\r\n")
+        }
+        "LRANGE" => match store.data.get(&args[0]) {
+            Some(Value::List(values)) => {
+                format!("*{}\r\n{}", values.len(), values.iter().map(|v| bulk(Some(v))).collect::<String>())
+            }
+            Some(_) => wrong_type(),
+            None => "*0\r\n".to_owned(),
+        },
+        "XADD" => {
+            let Some(stream) = stream_mut(store, &args[0], true) else {
+                return wrong_type()
+            };
+            // XADD key [MAXLEN [~|=] n] * field value ...
+            let mut rest = &args[1..];
+            let mut max_len = None;
+            if rest[0].eq_ignore_ascii_case("MAXLEN") {
+                let skip = if rest[1] == "~" || rest[1] == "=" { 2 } else { 1 };
+                max_len = rest[skip].parse::<usize>().ok();
+                rest = &rest[skip + 1..];
+            }
+            stream.last_id = StreamEntryId(stream.last_id.0 + 1, 0);
+            let fields = rest[1..].chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+            stream.entries.push((stream.last_id, fields));
+            if let Some(max_len) = max_len && stream.entries.len() > max_len {
+                let excess = stream.entries.len() - max_len;
+                stream.entries.drain(..excess);
+            }
+            let id = stream.last_id.to_string();
+            store.touch(&args[0]);
+            bulk(Some(&id))
+        }
+        "XGROUP" if args[0].eq_ignore_ascii_case("CREATE") => {
+            let mkstream = args.iter().any(|arg| arg.eq_ignore_ascii_case("MKSTREAM"));
+            let Some(stream) = stream_mut(store, &args[1], mkstream) else {
+                return "-ERR The XGROUP subcommand requires the key to exist\r\n".to_owned()
+            };
+            if stream.groups.contains_key(&args[2]) {
+                return "-BUSYGROUP Consumer Group name already exists\r\n".to_owned()
+            }
+            let last_delivered = if args[3] == "$" { stream.last_id } else { StreamEntryId::parse(&args[3]).unwrap_or_default() };
+            stream.groups.insert(args[2].clone(), Group { last_delivered, ..Group::default() });
+            "+OK\r\n".to_owned()
+        }
+        "XREADGROUP" => {
+            // XREADGROUP GROUP group consumer [COUNT n] [BLOCK ms] STREAMS key >
+            let option = |name: &str| args.iter().position(|arg| arg.eq_ignore_ascii_case(name)).map(|i| &args[i + 1]);
+            let (group, consumer) = (&args[1], &args[2]);
+            let count = option("COUNT").and_then(|count| count.parse().ok()).unwrap_or(usize::MAX);
+            let key = option("STREAMS").unwrap();
+            let Some(stream) = stream_mut(store, key, false) else {
+                return "*-1\r\n".to_owned()
+            };
+            let Some(state) = stream.groups.get_mut(group) else {
+                return "-NOGROUP No such consumer group\r\n".to_owned()
+            };
+            let read: Vec<&StreamEntry> = stream.entries.iter()
+                .filter(|(id, _)| *id > state.last_delivered)
+                .take(count)
+                .collect();
+            // Not blocking, an empty read answers at once as if BLOCK timed out
+            let Some((last, _)) = read.last() else {
+                return "*-1\r\n".to_owned()
+            };
+            state.last_delivered = *last;
+            for (id, _) in &read {
+                state.pending.insert(*id, Pending { consumer: consumer.clone(), delivered_at: Instant::now(), deliveries: 1 });
+            }
+            array(&[array(&[bulk(Some(key)), entries(&read)])])
+        }
+        "XPENDING" => {
+            // XPENDING key group start end count
+            let Some(stream) = stream_mut(store, &args[0], false) else {
+                return "*0\r\n".to_owned()
+            };
+            let count = args.get(4).and_then(|count| count.parse().ok()).unwrap_or(usize::MAX);
+            let pending: Vec<String> = stream.groups.get(&args[1]).into_iter()
+                .flat_map(|group| group.pending.iter())
+                .take(count)
+                .map(|(id, pending)| array(&[
+                    bulk(Some(&id.to_string())),
+                    bulk(Some(&pending.consumer)),
+                    format!(":{}\r\n", pending.delivered_at.elapsed().as_millis()),
+                    format!(":{}\r\n", pending.deliveries),
+                ]))
+                .collect();
+            array(&pending)
+        }
+        "XCLAIM" => {
+            // XCLAIM key group consumer min-idle-time id ...
+            let Some(stream) = stream_mut(store, &args[0], false) else {
+                return "*0\r\n".to_owned()
+            };
+            let min_idle: u128 = args[3].parse().unwrap_or_default();
+            let Some(group) = stream.groups.get_mut(&args[1]) else {
+                return "-NOGROUP No such consumer group\r\n".to_owned()
+            };
+            let mut claimed = Vec::new();
+            for id in args[4..].iter().filter_map(|id| StreamEntryId::parse(id)) {
+                let Some(pending) = group.pending.get_mut(&id) else {
+                    continue
+                };
+                if pending.delivered_at.elapsed().as_millis() < min_idle {
+                    continue
+                }
+                *pending = Pending { consumer: args[2].clone(), delivered_at: Instant::now(), deliveries: pending.deliveries + 1 };
+                claimed.extend(stream.entries.iter().find(|(entry_id, _)| *entry_id == id));
+            }
+            entries(&claimed)
+        }
+        "XACK" => {
+            let Some(group) = stream_mut(store, &args[0], false).and_then(|stream| stream.groups.get_mut(&args[1])) else {
+                return ":0\r\n".to_owned()
+            };
+            let acked = args[2..].iter()
+                .filter_map(|id| StreamEntryId::parse(id))
+                .filter(|id| group.pending.remove(id).is_some())
+                .count();
+            format!(":{acked}\r\n")
+        }
+        "PING" => args.first().map(|message| bulk(Some(message))).unwrap_or_else(|| "+PONG\r\n".to_owned()),
+        // Connection setup like CLIENT SETINFO
+        _ => "+OK\r\n".to_owned(),
+    }
+}
+
+fn stream_mut<'a>(store: &'a mut Store, key: &str, create: bool) -> Option<&'a mut Stream> {
+    if create {
+        store.data.entry(key.to_owned()).or_insert_with(|| Value::Stream(Stream::default()));
+    }
+    match store.data.get_mut(key) {
+        Some(Value::Stream(stream)) => Some(stream),
+        _ => None,
+    }
+}
+
+fn entries(entries: &[&StreamEntry]) -> String {
+    let entries: Vec<String> = entries.iter()
+        .map(|(id, fields)| {
+            let fields: Vec<String> = fields.iter().flat_map(|(field, value)| [bulk(Some(field)), bulk(Some(value))]).collect();
+            array(&[bulk(Some(&id.to_string())), array(&fields)])
+        })
+        .collect();
+    array(&entries)
+}
+
+fn array(items: &[String]) -> String {
+    format!("*{}\r\n{}", items.len(), items.concat())
+}
+
+fn bulk(value: Option<&String>) -> String {
+    match value {
+        Some(value) => format!("${}\r\n{}\r\n", value.len(), value),
+        None => "$-1\r\n".to_owned(),
+    }
+}
+
+fn wrong_type() -> String {
+    "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_owned()
+}
+
+fn invalid_expire() -> String {
+    "-ERR invalid expire time in 'set' command\r\n".to_owned()
+}
+
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line).await {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end().to_owned()),
+    }
+}
+
+async fn read_command<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<Vec<String>> {
+    let count: usize = read_line(reader).await?.strip_prefix('*')?.parse().ok()?;
+    let mut command = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len: usize = read_line(reader).await?.strip_prefix('$')?.parse().ok()?;
+        let mut value = vec![0; len + 2];
+        reader.read_exact(&mut value).await.ok()?;
+        value.truncate(len);
+        command.push(String::from_utf8(value).ok()?);
+    }
+    Some(command)
+}
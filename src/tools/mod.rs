@@ -1,24 +1,51 @@
 pub mod send_mode_client;
+pub mod retry;
+pub mod signing;
+#[cfg(test)]
+pub(crate) mod fake_redis;
 
-use std::time::Duration;
-use tokio_retry2::{Retry, RetryError};
-use tokio_retry2::strategy::{ExponentialBackoff, FixedInterval};
+use tokio_retry2::RetryError;
 use tracing::{debug, error, warn};
-use crate::send_modes::error::LibError;
+use crate::send_modes::error::{LibError, ResponseDetails};
+use crate::tools::retry::RetryPolicy;
 
-async fn send_request_for_retry(client: &reqwest::Client, request: reqwest::Request)
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+async fn send_request_for_retry(client: &reqwest::Client, request: reqwest::Request, policy: &RetryPolicy, idempotent: bool)
                                 -> Result<reqwest::Response, RetryError<LibError>>
 {
     let url = request.url().to_string();
-    match tokio::time::timeout(Duration::from_millis(500), client.execute(request)).await {
+    match tokio::time::timeout(policy.attempt_timeout, client.execute(request)).await {
+        Ok(Ok(response)) if policy.is_retryable_status(response.status().as_u16()) && idempotent => {
+            warn!(url=url, code=response.status().as_u16(), "Request got retryable status. Retrying...");
+            let retry_after = retry::retry_after(&response);
+            let err = match error_for_status(response).await {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            match retry_after {
+                Some(delay) => Err(RetryError::retry_after(err, delay)),
+                None => Err(RetryError::transient(err)),
+            }
+        }
         Ok(Ok(response)) => Ok(response),
-        Ok(Err(e)) => {
+        // The request was not sent, so it is safe to retry even a non-idempotent one
+        Ok(Err(e)) if idempotent || e.is_connect() => {
             warn!(err=e.to_string(), url=url, "Request send error. Retrying...");
-            return Err(RetryError::to_transient(LibError::IOError(e))?) // TODO: тут возможна ошибка
+            Err(RetryError::transient(LibError::IOError(e)))
         }
-        Err(_) => {
+        Ok(Err(e)) => {
+            error!(err=e.to_string(), url=url, "Request send error");
+            Err(RetryError::permanent(LibError::IOError(e)))
+        }
+        Err(_) if idempotent => {
             warn!(url=url, "Request send timed out. Retrying...");
-            return Err(RetryError::to_transient(LibError::TimeOut)?)
+            Err(RetryError::transient(LibError::TimeOut))
+        }
+        Err(_) => {
+            error!(url=url, "Request send timed out");
+            Err(RetryError::permanent(LibError::TimeOut))
         }
     }
 }
@@ -26,31 +53,59 @@ async fn send_request_for_retry(client: &reqwest::Client, request: reqwest::Requ
 pub async fn send_request(client: &reqwest::Client, request: reqwest::Request)
                           -> Result<reqwest::Response, LibError>
 {
-    let request = match request.try_clone() {
-        Some(req) => req,
-        None => {
+    send_request_with_policy(client, request, &RetryPolicy::default()).await
+}
+
+pub async fn send_request_with_policy(client: &reqwest::Client, request: reqwest::Request, policy: &RetryPolicy)
+                                      -> Result<reqwest::Response, LibError>
+{
+    let idempotent = policy.is_idempotent(request.method())
+        || (policy.retry_with_idempotency_key && request.headers().contains_key(IDEMPOTENCY_KEY_HEADER));
+    let start = tokio::time::Instant::now();
+    let mut delays = policy.delays();
+    let resp = loop {
+        let Some(attempt) = request.try_clone() else {
             error!("error clone request");
-            return Err(LibError::InternalServerError)?
+            break Err(LibError::InternalServerError)
+        };
+        let (err, retry_after) = match send_request_for_retry(client, attempt, policy, idempotent).await {
+            Ok(response) => break Ok(response),
+            Err(RetryError::Permanent(err)) => break Err(err),
+            Err(RetryError::Transient { err, retry_after }) => (err, retry_after),
+        };
+        let Some(delay) = delays.next() else {
+            break Err(err)
+        };
+        let delay = retry_after.map_or(delay, |retry_after| retry_after.max(delay));
+        if policy.max_elapsed.is_some_and(|max_elapsed| start.elapsed() + delay > max_elapsed) {
+            warn!("Request retry time exceeded");
+            break Err(err)
         }
+        tokio::time::sleep(delay).await;
     };
-    let start = tokio::time::Instant::now();
-    let retry_strategy = FixedInterval::from_millis(100)
-        .take(5);
-    let resp = Retry::spawn(retry_strategy, move || {
-        let client = client.clone();
-        let request = request.try_clone().ok_or(RetryError::Permanent(LibError::InternalServerError));
-
-        async move {
-            let req = request?;
-            send_request_for_retry(&client, req).await
-        }
-    }).await;
     let elapsed = start.elapsed();
     debug!("request elapsed in {}", elapsed.as_millis());
     resp
 }
 
-
+pub async fn error_for_status(response: reqwest::Response) -> Result<reqwest::Response, LibError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response)
+    }
+    let url = response.url().to_string();
+    let request_id = response.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let body = response.text().await.unwrap_or_default();
+    error!(url=url, code=status.as_u16(), request_id=request_id, body=body, "send mode error");
+    Err(LibError::from_response(ResponseDetails {
+        status: status.as_u16(),
+        request_id,
+        body,
+    }))
+}
 
 #[macro_export]
 macro_rules! retry {
@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Method, Response};
+use tokio_retry2::strategy::{jitter, ExponentialFactorBackoff};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub attempt_timeout: Duration,
+    pub max_retries: usize,
+    pub initial_interval: Duration,
+    pub multiplier: f64,
+    pub max_interval: Duration,
+    pub jitter: bool,
+    pub max_elapsed: Option<Duration>,
+    pub retryable_statuses: HashSet<u16>,
+    pub idempotent_methods: HashSet<Method>,
+    /// Set it only for servers that deduplicate requests by the idempotency key.
+    pub retry_with_idempotency_key: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempt_timeout: Duration::from_millis(500),
+            max_retries: 5,
+            initial_interval: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_interval: Duration::from_secs(2),
+            jitter: true,
+            max_elapsed: Some(Duration::from_secs(10)),
+            retryable_statuses: HashSet::from([429, 502, 503, 504]),
+            idempotent_methods: HashSet::from([
+                Method::GET,
+                Method::HEAD,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+                Method::TRACE,
+            ]),
+            retry_with_idempotency_key: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retry() -> Self {
+        Self { max_retries: 0, ..Self::default() }
+    }
+
+    pub fn delays(&self) -> impl Iterator<Item = Duration> + use<> {
+        let use_jitter = self.jitter;
+        ExponentialFactorBackoff::from_millis(self.initial_interval.as_millis() as u64, self.multiplier)
+            .max_delay(self.max_interval)
+            .map(move |delay| if use_jitter { jitter(delay) } else { delay })
+            .take(self.max_retries)
+    }
+
+    pub fn is_idempotent(&self, method: &Method) -> bool {
+        self.idempotent_methods.contains(method)
+    }
+
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+}
+
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds))
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    (at - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_max_interval() {
+        let policy = RetryPolicy { jitter: false, max_retries: 6, ..RetryPolicy::default() };
+        let delays: Vec<u128> = policy.delays().map(|d| d.as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1600, 2000]);
+        assert_eq!(RetryPolicy::no_retry().delays().count(), 0);
+    }
+
+    #[test]
+    fn post_is_not_idempotent() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_idempotent(&Method::GET));
+        assert!(!policy.is_idempotent(&Method::POST));
+        assert!(policy.is_retryable_status(503));
+        assert!(!policy.is_retryable_status(500));
+    }
+}
@@ -1,111 +1,363 @@
 use std::env;
-use once_cell::sync::Lazy;
-use reqwest::Client;
-use tracing::error;
-use crate::send_modes;
+use std::time::Duration;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, USER_AGENT};
+use reqwest::{Client, Method, RequestBuilder, Url};
+use tracing::{debug, error};
 use crate::send_modes::error::LibError;
-use crate::send_modes::error::LibError::{IOError, InternalServerError};
-use crate::send_modes::send_mode::{NewSendModeRequest, SendMode};
-use crate::tools::send_request;
+use crate::send_modes::error::LibError::InternalServerError;
+use crate::send_modes::send_mode::{ListSendModesRequest, NewSendModeRequest, RenameSendModeRequest, SendMode, SendModePage, UpdateSendModeRequest};
+use crate::tools::{error_for_status, send_request_with_policy, IDEMPOTENCY_KEY_HEADER};
+use crate::tools::retry::RetryPolicy;
+use crate::tools::signing::RequestSigner;
 
-const SEND_MODE_URL: Lazy<String> = Lazy::new(|| {
-    env::var("SEND_MODE_URL").expect("Set SEND_MODE_URL environment variable")
-});
+pub const SEND_MODE_URL_ENV: &str = "SEND_MODE_URL";
+pub const DEFAULT_USER_AGENT: &str = concat!("send_mode_lib/", env!("CARGO_PKG_VERSION"));
 
 pub struct SendModeClient {
-    client: Client
+    client: Client,
+    base_url: String,
+    headers: HeaderMap,
+    retry_policy: RetryPolicy,
+    signer: Option<RequestSigner>,
 }
+
+#[derive(Default)]
+pub struct SendModeClientBuilder {
+    base_url: Option<String>,
+    client: Option<Client>,
+    timeout: Option<Duration>,
+    retry_policy: Option<RetryPolicy>,
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+    signer: Option<RequestSigner>,
+}
+
+impl SendModeClientBuilder {
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn signer(mut self, signer: RequestSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    pub fn build(self) -> Result<SendModeClient, LibError> {
+        let base_url = self.base_url
+            .ok_or_else(|| LibError::InvalidConfig("base url is not set".to_owned()))?;
+        let parsed = Url::parse(&base_url)
+            .map_err(|e| LibError::InvalidConfig(format!("invalid base url {base_url}: {e}")))?;
+        if parsed.cannot_be_a_base() || !matches!(parsed.scheme(), "http" | "https") {
+            return Err(LibError::InvalidConfig(format!("invalid base url {base_url}")))
+        }
+
+        let mut headers = HeaderMap::new();
+        let user_agent = self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        headers.insert(USER_AGENT, HeaderValue::from_str(user_agent)
+            .map_err(|_| LibError::InvalidConfig(format!("invalid user agent {user_agent}")))?);
+        for (name, value) in &self.headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| LibError::InvalidConfig(format!("invalid header name {name}")))?;
+            let header_value = HeaderValue::from_str(value)
+                .map_err(|_| LibError::InvalidConfig(format!("invalid value of header {name}")))?;
+            headers.insert(header_name, header_value);
+        }
+
+        let mut retry_policy = self.retry_policy.unwrap_or_default();
+        if let Some(timeout) = self.timeout {
+            retry_policy.attempt_timeout = timeout;
+        }
+        if retry_policy.attempt_timeout.is_zero() {
+            return Err(LibError::InvalidConfig("timeout must be positive".to_owned()))
+        }
+
+        Ok(SendModeClient {
+            client: self.client.unwrap_or_default(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            headers,
+            retry_policy,
+            signer: self.signer,
+        })
+    }
+}
+
 impl SendModeClient {
-    pub fn new() -> Self {
-        Self {
-            client: Client::new()
+    pub fn builder() -> SendModeClientBuilder {
+        SendModeClientBuilder::default()
+    }
+
+    pub fn from_env() -> Result<Self, LibError> {
+        let base_url = env::var(SEND_MODE_URL_ENV)
+            .map_err(|_| LibError::InvalidConfig(format!("{SEND_MODE_URL_ENV} environment variable is not set")))?;
+        Self::builder().base_url(base_url).build()
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client.request(method, format!("{}{}", self.base_url, path))
+            .headers(self.headers.clone())
+    }
+
+    async fn send(&self, mut request: reqwest::Request) -> Result<reqwest::Response, LibError> {
+        if let Some(signer) = &self.signer {
+            signer.sign(&mut request)?;
         }
+        error_for_status(send_request_with_policy(&self.client, request, &self.retry_policy).await?).await
     }
-    pub async fn new_send_mode(&self, request: NewSendModeRequest)
+
+    /// Generates the idempotency key into `request` when not set, so the caller can repeat the request with it.
+    pub async fn new_send_mode(&self, request: &mut NewSendModeRequest)
     -> Result<SendMode, LibError>
     {
-        let request = self.client.post(format!("{}/api/v1/send_modes", *SEND_MODE_URL).as_str())
-            .body(request).build()?;
-        let response = send_request(&self.client, request).await?;
-        match response.error_for_status() {
-            Ok(response) => {
-                let payload = response.text().await?;
-                Ok(serde_json::from_str::<SendMode>(&payload).map_err(|e| {
-                    error!(err=e.to_string(), "body serialize error");
-                    InternalServerError
-                })?)
-            },
-            Err(e) => {
-                error!(err=e.to_string(), "send mode error");
-                Err(InternalServerError)
+        let idempotency_key = request.ensure_idempotency_key().to_owned();
+        let http_request = self.request(Method::POST, "/api/v1/send_modes")
+            .header(IDEMPOTENCY_KEY_HEADER, idempotency_key.as_str())
+            .body(&*request).build()?;
+        let response = match self.send(http_request).await {
+            Ok(response) => response,
+            Err(LibError::Conflict(details)) => {
+                return match serde_json::from_str::<SendMode>(&details.body) {
+                    Ok(send_mode) if request.is_created_as(&send_mode) => {
+                        debug!(id=send_mode.id, idempotency_key=idempotency_key, "Send mode already created");
+                        Ok(send_mode)
+                    }
+                    _ => Err(LibError::Conflict(details)),
+                }
             }
-        }
+            Err(e) => return Err(e),
+        };
+        let payload = response.text().await?;
+        serde_json::from_str::<SendMode>(&payload).map_err(|e| {
+            error!(err=e.to_string(), "body serialize error");
+            InternalServerError
+        })
     }
     pub async fn get_send_mode_by_id(&self, send_mode_id: &str)
                                -> Result<SendMode, LibError>
     {
-        let request = self.client.get(format!("{}/api/v1/send_modes/{}", *SEND_MODE_URL, send_mode_id).as_str()).build()?;
-        let response = send_request(&self.client, request).await?;
-        match response.error_for_status() {
-            Ok(response) => {
-                let payload = response.text().await?;
-                Ok(serde_json::from_str::<SendMode>(&payload).map_err(|e| {
-                    error!(err=e.to_string(), "body serialize error");
-                    InternalServerError
-                })?)
-            },
-            Err(e) => {
-                error!(err=e.to_string(), "send mode error");
-                Err(InternalServerError)
-            }
-        }
+        let request = self.request(Method::GET, &format!("/api/v1/send_modes/{}", send_mode_id)).build()?;
+        let response = self.send(request).await?;
+        let payload = response.text().await?;
+        serde_json::from_str::<SendMode>(&payload).map_err(|e| {
+            error!(err=e.to_string(), "body serialize error");
+            InternalServerError
+        })
     }
     pub async fn heartbeat(&self, send_mode_id: &str)
                                      -> Result<(), LibError>
     {
-        let request = self.client.get(format!("{}/api/v1/send_modes/{}/heartbeat", *SEND_MODE_URL, send_mode_id).as_str()).build()?;
-        let response = send_request(&self.client, request).await?;
-        match response.error_for_status() {
-            Ok(response) => {
-                return Ok(())
-            },
-            Err(e) => {
-                error!(err=e.to_string(), "send mode error");
-                Err(InternalServerError)
-            }
-        }
+        let request = self.request(Method::GET, &format!("/api/v1/send_modes/{}/heartbeat", send_mode_id)).build()?;
+        self.send(request).await?;
+        Ok(())
     }
     pub async fn get_send_mode_by_aggregate_id(&self, send_mode_id: &str)
                                      -> Result<Vec<SendMode>, LibError>
     {
-        let request = self.client.get(format!("{}/api/v1/send_modes/aggregate_id/{}", *SEND_MODE_URL, send_mode_id).as_str()).build()?;
-        let response = send_request(&self.client, request).await?;
-        match response.error_for_status() {
-            Ok(response) => {
-                let payload = response.text().await?;
-                Ok(serde_json::from_str::<Vec<SendMode>>(&payload).map_err(|e| {
-                    error!(err=e.to_string(), "body serialize error");
-                    InternalServerError
-                })?)
-            },
-            Err(e) => {
-                error!(err=e.to_string(), "send mode error");
-                Err(InternalServerError)
-            }
-        }
+        let request = self.request(Method::GET, &format!("/api/v1/send_modes/aggregate_id/{}", send_mode_id)).build()?;
+        let response = self.send(request).await?;
+        let payload = response.text().await?;
+        serde_json::from_str::<Vec<SendMode>>(&payload).map_err(|e| {
+            error!(err=e.to_string(), "body serialize error");
+            InternalServerError
+        })
     }
     pub async fn delete_send_mode(&self, send_mode_id: &str) -> Result<(), LibError>
     {
-        let request = self.client.delete(format!("{}/api/v1/send_modes/{}", *SEND_MODE_URL, send_mode_id).as_str()).build()?;
-        let response = send_request(&self.client, request).await?;
-        match response.error_for_status() {
-            Ok(_) => {
-                Ok(())
-            },
-            Err(e) => {
-                error!(err=e.to_string(), "send mode error");
-                Err(InternalServerError)
+        let request = self.request(Method::DELETE, &format!("/api/v1/send_modes/{}", send_mode_id)).build()?;
+        self.send(request).await?;
+        Ok(())
+    }
+    pub async fn rename_send_mode(&self, request: &RenameSendModeRequest) -> Result<SendMode, LibError>
+    {
+        let request = self.request(Method::PUT, &format!("/api/v1/send_modes/{}/name", request.id))
+            .header(CONTENT_TYPE, "application/json")
+            .body(request).build()?;
+        let response = self.send(request).await?;
+        let payload = response.text().await?;
+        serde_json::from_str::<SendMode>(&payload).map_err(|e| {
+            error!(err=e.to_string(), "body serialize error");
+            InternalServerError
+        })
+    }
+    pub async fn update_send_mode(&self, send_mode_id: &str, request: &UpdateSendModeRequest)
+                                  -> Result<SendMode, LibError>
+    {
+        let request = self.request(Method::PATCH, &format!("/api/v1/send_modes/{}", send_mode_id))
+            .header(CONTENT_TYPE, "application/json")
+            .body(request).build()?;
+        let response = self.send(request).await?;
+        let payload = response.text().await?;
+        serde_json::from_str::<SendMode>(&payload).map_err(|e| {
+            error!(err=e.to_string(), "body serialize error");
+            InternalServerError
+        })
+    }
+    pub async fn list_send_modes(&self, request: &ListSendModesRequest) -> Result<SendModePage, LibError>
+    {
+        let request = self.request(Method::GET, "/api/v1/send_modes")
+            .query(request).build()?;
+        let response = self.send(request).await?;
+        let payload = response.text().await?;
+        serde_json::from_str::<SendModePage>(&payload).map_err(|e| {
+            error!(err=e.to_string(), "body serialize error");
+            InternalServerError
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use crate::send_modes::fixtures;
+    use crate::send_modes::send_mode::SendModeEnum;
+    use super::*;
+
+    /// Answers with `(status, body)` in turn, `None` never answers. Returns the base url
+    /// and the idempotency keys of the received requests.
+    async fn serve(replies: Vec<Option<(u16, String)>>) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let keys = Arc::new(Mutex::new(Vec::new()));
+        let received = keys.clone();
+        let mut replies = VecDeque::from(replies);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let (mut key, mut length) = (None, 0);
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break
+                    }
+                    if let Some((name, value)) = line.split_once(": ") {
+                        match name.to_lowercase().as_str() {
+                            IDEMPOTENCY_KEY_HEADER => key = Some(value.to_owned()),
+                            "content-length" => length = value.parse().unwrap(),
+                            _ => {}
+                        }
+                    }
+                }
+                stream.read_exact(&mut vec![0; length]).await.unwrap();
+                received.lock().unwrap().push(key);
+                let Some((status, body)) = replies.pop_front().flatten() else {
+                    // Keep the connection open without answering
+                    tokio::spawn(async move { tokio::time::sleep(Duration::from_secs(60)).await; drop(stream) });
+                    continue
+                };
+                let response = format!("HTTP/1.1 {status} X\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len());
+                stream.write_all(response.as_bytes()).await.unwrap();
             }
+        });
+        (url, keys)
+    }
+
+    fn new_request() -> NewSendModeRequest {
+        NewSendModeRequest {
+            aggregate_id: "aggregate".to_owned(),
+            name: "phone".to_owned(),
+            mode: SendModeEnum::KRAFT,
+            access_token: "token".to_owned(),
+            auto_heartbeat_interval: None,
+            idempotency_key: None,
         }
     }
-}
\ No newline at end of file
+
+    fn send_mode(name: &str, access_token: &str) -> String {
+        serde_json::to_string(&SendMode {
+            name: name.to_owned(),
+            access_token: access_token.to_owned(),
+            ..fixtures::send_mode("id")
+        }).unwrap()
+    }
+
+    fn client(url: &str, retry_with_idempotency_key: bool) -> SendModeClient {
+        let retry_policy = RetryPolicy {
+            attempt_timeout: Duration::from_millis(200),
+            initial_interval: Duration::from_millis(1),
+            jitter: false,
+            retry_with_idempotency_key,
+            ..RetryPolicy::default()
+        };
+        SendModeClient::builder().base_url(url).retry_policy(retry_policy).build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn new_send_mode_returns_generated_key_and_retries_only_when_enabled() {
+        let (url, keys) = serve(vec![None]).await;
+        let mut request = new_request();
+        assert!(matches!(client(&url, false).new_send_mode(&mut request).await, Err(LibError::TimeOut)));
+        let key = request.idempotency_key.clone().unwrap();
+        assert_eq!(*keys.lock().unwrap(), vec![Some(key)]);
+
+        let (url, keys) = serve(vec![None, Some((200, send_mode("phone", "token")))]).await;
+        let mut request = new_request().with_idempotency_key("key");
+        let created = client(&url, true).new_send_mode(&mut request).await.unwrap();
+        assert_eq!(created.name, "phone");
+        assert_eq!(*keys.lock().unwrap(), vec![Some("key".to_owned()), Some("key".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn new_send_mode_accepts_only_matching_conflict() {
+        let (url, _) = serve(vec![
+            Some((409, send_mode("phone", "token"))),
+            Some((409, send_mode("other", "token"))),
+            Some((409, send_mode("phone", "existing"))),
+            Some((409, "name is taken".to_owned())),
+        ]).await;
+        let client = client(&url, true);
+        assert_eq!(client.new_send_mode(&mut new_request()).await.unwrap().id, "id");
+        assert!(matches!(client.new_send_mode(&mut new_request()).await, Err(LibError::Conflict(_))));
+        assert!(matches!(client.new_send_mode(&mut new_request()).await, Err(LibError::Conflict(_))));
+        let Err(LibError::Conflict(details)) = client.new_send_mode(&mut new_request()).await else {
+            panic!("conflict expected")
+        };
+        assert_eq!(details.body, "name is taken");
+    }
+
+    #[test]
+    fn builder_validates_config() {
+        assert!(matches!(SendModeClient::builder().build(), Err(LibError::InvalidConfig(_))));
+        assert!(SendModeClient::builder().base_url("not a url").build().is_err());
+        assert!(SendModeClient::builder().base_url("http://localhost").header("bad header", "x").build().is_err());
+
+        let client = SendModeClient::builder()
+            .base_url("http://localhost:8080/")
+            .timeout(Duration::from_secs(2))
+            .header("x-api-key", "secret")
+            .build()
+            .unwrap();
+        assert_eq!(client.base_url, "http://localhost:8080");
+        assert_eq!(client.retry_policy.attempt_timeout, Duration::from_secs(2));
+        assert_eq!(client.headers.get("x-api-key").unwrap(), "secret");
+    }
+}